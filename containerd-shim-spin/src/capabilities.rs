@@ -0,0 +1,493 @@
+use anyhow::{bail, Context, Result};
+use spin_app::locked::{LockedApp, LockedComponent, LockedTrigger};
+use wasmparser::{Encoding, Parser, Payload};
+
+use crate::{
+    compose,
+    trigger::{
+        self, COMMAND_TRIGGER_TYPE, HTTP_TRIGGER_TYPE, MQTT_TRIGGER_TYPE, REDIS_TRIGGER_TYPE,
+    },
+};
+
+/// Returns the interfaces of which the component of `trigger` must export one to be run by it,
+/// or `None` if the trigger type is not checked. The SQS trigger plugin does not define the
+/// exports it calls and is not checked.
+///
+/// The HTTP trigger runs components with the executor set in its config: WAGI components are
+/// run as commands, while the default executor calls a handler export.
+fn required_exports(trigger: &LockedTrigger) -> Option<&'static [&'static str]> {
+    match trigger.trigger_type.as_str() {
+        HTTP_TRIGGER_TYPE => {
+            let executor = trigger
+                .trigger_config
+                .get("executor")
+                .and_then(|executor| executor.get("type"))
+                .and_then(|executor_type| executor_type.as_str());
+            match executor {
+                Some("wagi") => Some(&["wasi:cli/run"]),
+                _ => Some(&["wasi:http/incoming-handler", "fermyon:spin/inbound-http"]),
+            }
+        }
+        REDIS_TRIGGER_TYPE => Some(&["fermyon:spin/inbound-redis"]),
+        COMMAND_TRIGGER_TYPE => Some(&["wasi:cli/run"]),
+        MQTT_TRIGGER_TYPE => Some(&["spin:mqtt-trigger/spin-mqtt-trigger"]),
+        _ => None,
+    }
+}
+
+/// Checks that the component of every trigger of `locked_app` exports one of the interfaces the
+/// trigger calls, failing with the interfaces each incompatible component is missing. Imports are
+/// checked by [`check_component_imports`].
+///
+/// Core modules are skipped, as they are adapted to the trigger when componentized at load time.
+/// Precompiled sources are only read if `load_aot_compiled`, see [`component_interfaces`].
+pub(crate) async fn check_trigger_exports(
+    locked_app: &LockedApp,
+    engine: &wasmtime::Engine,
+    load_aot_compiled: bool,
+) -> Result<()> {
+    let mut incompatible = Vec::new();
+    for trigger in &locked_app.triggers {
+        let Some(required) = required_exports(trigger) else {
+            continue;
+        };
+        let Some(component) = trigger
+            .trigger_config
+            .get("component")
+            .and_then(|id| id.as_str())
+            .and_then(|id| locked_app.components.iter().find(|c| c.id == id))
+        else {
+            continue;
+        };
+        let Some(interfaces) = component_interfaces(component, engine, load_aot_compiled)
+            .await
+            .with_context(|| format!("failed to read exports of component {:?}", component.id))?
+        else {
+            continue;
+        };
+
+        let exports_required = required.iter().any(|required| {
+            interfaces
+                .exports
+                .iter()
+                .any(|export| split_interface(export) == split_interface(required))
+        });
+        if !exports_required {
+            incompatible.push(format!(
+                "component {:?} of {} trigger {:?} does not export any of {}",
+                component.id,
+                trigger.trigger_type,
+                trigger.id,
+                required.join(", ")
+            ));
+        }
+    }
+    if !incompatible.is_empty() {
+        bail!(
+            "application is not compatible with the environment hosted by the shim:\n  {}",
+            incompatible.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+/// Checks that every component of `locked_app` can be instantiated by the shim's host, failing
+/// with the reason for each component that cannot. Components are pre-instantiated with a linker
+/// set up by the same runtime factors as the triggers, so the check follows what the host
+/// actually provides. This catches components that would otherwise only fail once their trigger
+/// starts.
+///
+/// Components that are not precompiled are compiled for the check, after being componentized, or
+/// composed with their dependencies. Precompiled components are only loaded if
+/// `load_aot_compiled`, when they were produced by the shim; otherwise the check fails, as Spin
+/// would fail to load them.
+pub(crate) async fn check_component_imports(
+    locked_app: &LockedApp,
+    load_aot_compiled: bool,
+) -> Result<()> {
+    let engine = trigger::host_engine().context("failed to set up the host linker")?;
+    let mut uninstantiable = Vec::new();
+    for component in &locked_app.components {
+        let Some(loaded) = load_component(component, engine.as_ref(), load_aot_compiled)
+            .await
+            .with_context(|| format!("failed to load component {:?}", component.id))?
+        else {
+            continue;
+        };
+        if let Err(err) = engine.instantiate_pre(&loaded) {
+            uninstantiable.push(format!("component {:?}: {err:#}", component.id));
+        }
+    }
+    if !uninstantiable.is_empty() {
+        bail!(
+            "application components import interfaces that are not provided by the shim:\n  {}",
+            uninstantiable.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+// Loads the component of `component` for `engine`, or returns `None` if its source is a
+// precompiled core module
+async fn load_component(
+    component: &LockedComponent,
+    engine: &wasmtime::Engine,
+    load_aot_compiled: bool,
+) -> Result<Option<wasmtime::component::Component>> {
+    let path = source_path(component)?;
+    let wasm = tokio::fs::read(&path)
+        .await
+        .with_context(|| format!("failed to read {path:?}"))?;
+    match wasmtime::Engine::detect_precompiled(&wasm) {
+        Some(wasmtime::Precompiled::Component) => {
+            ensure_loadable_precompiled(&path, load_aot_compiled)?;
+            // SAFETY: AOT loading is only enabled for applications whose precompiled sources
+            // were checked to be recorded as produced by the shim, see `precompiled::is_recorded`,
+            // or to be layers of the image the node config trusts.
+            let component = unsafe { wasmtime::component::Component::deserialize(engine, &wasm)? };
+            return Ok(Some(component));
+        }
+        Some(wasmtime::Precompiled::Module) => return Ok(None),
+        None => {}
+    }
+    let wasm = if component.dependencies.is_empty() {
+        spin_componentize::componentize_if_necessary(&wasm)?.into_owned()
+    } else {
+        compose::compose_component(component).await?
+    };
+    let engine = engine.clone();
+    tokio::task::spawn_blocking(move || wasmtime::component::Component::new(&engine, wasm))
+        .await?
+        .map(Some)
+}
+
+// Fails for the precompiled source at `path` unless AOT compiled sources may be loaded, as
+// precompiled artifacts not produced by the shim must never be loaded as native code
+fn ensure_loadable_precompiled(path: &std::path::Path, load_aot_compiled: bool) -> Result<()> {
+    if !load_aot_compiled {
+        bail!(
+            "component source {path:?} is a precompiled artifact, which is only loaded when produced by the shim; use the wasm source of the component instead"
+        );
+    }
+    Ok(())
+}
+
+// Returns the local path of the source of `component`
+fn source_path(component: &LockedComponent) -> Result<std::path::PathBuf> {
+    let source = component
+        .source
+        .content
+        .source
+        .as_deref()
+        .context("component has no source")?;
+    url::Url::parse(source)
+        .ok()
+        .and_then(|url| url.to_file_path().ok())
+        .with_context(|| format!("component has a non-file source {source:?}"))
+}
+
+/// Names of the top-level imports and exports of a component.
+#[derive(Debug, Default)]
+pub(crate) struct ComponentInterfaces {
+    pub(crate) imports: Vec<String>,
+    pub(crate) exports: Vec<String>,
+}
+
+/// Returns the imports and exports of a component, or `None` if it is a core module.
+///
+/// The interfaces of precompiled components are read by loading them as native code, so this
+/// fails for precompiled sources unless `load_aot_compiled`, when they are known to be produced
+/// by the shim.
+pub(crate) async fn component_interfaces(
+    component: &LockedComponent,
+    engine: &wasmtime::Engine,
+    load_aot_compiled: bool,
+) -> Result<Option<ComponentInterfaces>> {
+    if component.source.content.source.is_none() {
+        return Ok(None);
+    }
+    let path = source_path(component)?;
+    let wasm = tokio::fs::read(&path)
+        .await
+        .with_context(|| format!("failed to read {path:?}"))?;
+
+    match wasmtime::Engine::detect_precompiled(&wasm) {
+        Some(wasmtime::Precompiled::Component) => {
+            ensure_loadable_precompiled(&path, load_aot_compiled)?;
+            // SAFETY: AOT loading is only enabled for applications whose precompiled sources
+            // were checked to be recorded as produced by the shim, see `precompiled::is_recorded`,
+            // or to be layers of the image the node config trusts.
+            let component = unsafe { wasmtime::component::Component::deserialize(engine, &wasm)? };
+            let component_type = component.component_type();
+            Ok(Some(ComponentInterfaces {
+                imports: component_type
+                    .imports(engine)
+                    .map(|(name, _)| name.to_string())
+                    .collect(),
+                exports: component_type
+                    .exports(engine)
+                    .map(|(name, _)| name.to_string())
+                    .collect(),
+            }))
+        }
+        Some(wasmtime::Precompiled::Module) => Ok(None),
+        None => parse_component_interfaces(&wasm),
+    }
+}
+
+// Returns the names of the top-level imports and exports of a component binary, or `None` if it
+// is a core module
+fn parse_component_interfaces(wasm: &[u8]) -> Result<Option<ComponentInterfaces>> {
+    let mut interfaces = ComponentInterfaces::default();
+    let mut depth = 0;
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload.context("failed to parse wasm")? {
+            Payload::Version { encoding, .. } => {
+                if depth == 0 && encoding == Encoding::Module {
+                    return Ok(None);
+                }
+                depth += 1;
+            }
+            Payload::End(_) => depth -= 1,
+            Payload::ComponentImportSection(reader) if depth == 1 => {
+                for import in reader {
+                    interfaces.imports.push(import?.name.0.to_string());
+                }
+            }
+            Payload::ComponentExportSection(reader) if depth == 1 => {
+                for export in reader {
+                    interfaces.exports.push(export?.name.0.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(Some(interfaces))
+}
+
+// Splits an import name such as `wasi:http/types@0.2.0` into its package (`wasi:http`) and
+// interface (`types`), ignoring the version
+pub(crate) fn split_interface(name: &str) -> (&str, Option<&str>) {
+    let name = name.split_once('@').map_or(name, |(name, _)| name);
+    match name.split_once('/') {
+        Some((package, interface)) => (package, Some(interface)),
+        None => (name, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_top_level_component_interfaces() {
+        let component = wat::parse_str(
+            r#"(component
+                (import "wasi:nn/graph@0.2.0" (instance))
+                (import "wasi:http/types@0.2.0" (instance $types))
+                (component (import "nested:pkg/iface" (instance)))
+                (export "wasi:cli/run@0.2.0" (instance $types))
+            )"#,
+        )
+        .unwrap();
+        let interfaces = parse_component_interfaces(&component).unwrap().unwrap();
+        assert_eq!(
+            interfaces.imports,
+            vec!["wasi:nn/graph@0.2.0", "wasi:http/types@0.2.0"]
+        );
+        assert_eq!(interfaces.exports, vec!["wasi:cli/run@0.2.0"]);
+
+        let module =
+            wat::parse_str(r#"(module (import "wasi_snapshot_preview1" "fd_write" (func)))"#)
+                .unwrap();
+        assert!(parse_component_interfaces(&module).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn uninstantiable_components_are_listed() {
+        let dir = tempfile::tempdir().unwrap();
+        let component = |name: &str, wat: &str| {
+            let wasm_path = dir.path().join(format!("{name}.wasm"));
+            std::fs::write(&wasm_path, wat::parse_str(wat).unwrap()).unwrap();
+            let source = url::Url::from_file_path(&wasm_path).unwrap();
+            format!(
+                r#"{{ "id": "{name}", "source": {{ "content_type": "application/wasm", "content": {{ "source": "{source}" }} }} }}"#
+            )
+        };
+        let app_json = format!(
+            r#"{{
+                "spin_lock_version": 1,
+                "components": [{}, {}],
+                "triggers": []
+            }}"#,
+            component(
+                "hello",
+                r#"(component (import "wasi:cli/environment@0.2.0" (instance)))"#
+            ),
+            component(
+                "inference",
+                r#"(component (import "wasi:nn/graph@0.2.0" (instance)))"#
+            ),
+        );
+        let locked_app = LockedApp::from_json(app_json.as_bytes()).unwrap();
+
+        let scratch_dir = tempfile::tempdir().unwrap();
+        let err = temp_env::async_with_vars(
+            [(
+                crate::constants::SPIN_SCRATCH_DIR_ENV,
+                Some(scratch_dir.path()),
+            )],
+            check_component_imports(&locked_app, false),
+        )
+        .await
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains(r#"component "inference""#) && err.contains("wasi:nn/graph@0.2.0"),
+            "unexpected error message: {err}"
+        );
+        assert!(
+            !err.contains(r#"component "hello""#),
+            "unexpected error message: {err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn untrusted_precompiled_components_are_not_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwasm_path = dir.path().join("component.cwasm");
+        let precompiled = wasmtime::Engine::default()
+            .precompile_component(&wat::parse_str("(component)").unwrap())
+            .unwrap();
+        std::fs::write(&cwasm_path, precompiled).unwrap();
+        let source = url::Url::from_file_path(&cwasm_path).unwrap();
+        let app_json = format!(
+            r#"{{
+                "spin_lock_version": 1,
+                "components": [{{
+                    "id": "hello",
+                    "source": {{ "content_type": "application/wasm", "content": {{ "source": "{source}" }} }}
+                }}],
+                "triggers": []
+            }}"#
+        );
+        let locked_app = LockedApp::from_json(app_json.as_bytes()).unwrap();
+
+        let scratch_dir = tempfile::tempdir().unwrap();
+        let err = temp_env::async_with_vars(
+            [(
+                crate::constants::SPIN_SCRATCH_DIR_ENV,
+                Some(scratch_dir.path()),
+            )],
+            check_component_imports(&locked_app, false),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("is a precompiled artifact"),
+            "unexpected error message: {err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn trigger_component_must_export_required_interface() {
+        let dir = tempfile::tempdir().unwrap();
+        let wasm_path = dir.path().join("component.wasm");
+        std::fs::write(
+            &wasm_path,
+            wat::parse_str(
+                r#"(component
+                    (import "wasi:cli/environment@0.2.0" (instance $env))
+                    (export "wasi:cli/run@0.2.0" (instance $env))
+                )"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let source = url::Url::from_file_path(&wasm_path).unwrap();
+        let app_json = |trigger_type: &str, trigger_config: &str| {
+            format!(
+                r#"{{
+                    "spin_lock_version": 1,
+                    "components": [{{
+                        "id": "hello",
+                        "source": {{ "content_type": "application/wasm", "content": {{ "source": "{source}" }} }}
+                    }}],
+                    "triggers": [{{ "id": "trigger", "trigger_type": "{trigger_type}", "trigger_config": {{ "component": "hello"{trigger_config} }} }}]
+                }}"#
+            )
+        };
+        let engine = wasmtime::Engine::default();
+
+        let locked_app =
+            LockedApp::from_json(app_json(COMMAND_TRIGGER_TYPE, "").as_bytes()).unwrap();
+        check_trigger_exports(&locked_app, &engine, false)
+            .await
+            .unwrap();
+
+        // WAGI components are commands
+        let locked_app = LockedApp::from_json(
+            app_json(
+                HTTP_TRIGGER_TYPE,
+                r#", "route": "/...", "executor": { "type": "wagi" }"#,
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        check_trigger_exports(&locked_app, &engine, false)
+            .await
+            .unwrap();
+
+        let locked_app =
+            LockedApp::from_json(app_json(HTTP_TRIGGER_TYPE, r#", "route": "/...""#).as_bytes())
+                .unwrap();
+        let err = check_trigger_exports(&locked_app, &engine, false)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("wasi:http/incoming-handler"),
+            "unexpected error message: {err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn untrusted_precompiled_exports_are_not_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwasm_path = dir.path().join("component.cwasm");
+        let engine = wasmtime::Engine::default();
+        let precompiled = engine
+            .precompile_component(&wat::parse_str("(component)").unwrap())
+            .unwrap();
+        std::fs::write(&cwasm_path, precompiled).unwrap();
+        let source = url::Url::from_file_path(&cwasm_path).unwrap();
+        let app_json = format!(
+            r#"{{
+                "spin_lock_version": 1,
+                "components": [{{
+                    "id": "hello",
+                    "source": {{ "content_type": "application/wasm", "content": {{ "source": "{source}" }} }}
+                }}],
+                "triggers": [{{ "id": "trigger", "trigger_type": "{COMMAND_TRIGGER_TYPE}", "trigger_config": {{ "component": "hello" }} }}]
+            }}"#
+        );
+        let locked_app = LockedApp::from_json(app_json.as_bytes()).unwrap();
+
+        let err = check_trigger_exports(&locked_app, &engine, false)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("is a precompiled artifact"),
+            "unexpected error message: {err:#}"
+        );
+        // Exports of trusted precompiled components are read from the loaded component
+        let err = check_trigger_exports(&locked_app, &engine, true)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("wasi:cli/run"),
+            "unexpected error message: {err}"
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Context, Result};
+use containerd_shim_wasm::sandbox::context::WasmLayer;
+use oci_spec::image::MediaType;
+use sha2::{Digest as _, Sha256};
+use spin_app::locked::{LockedApp, LockedComponent, LockedComponentSource};
+
+/// Loads component and dependency sources from their local files.
+struct FileSourceLoader;
+
+#[async_trait::async_trait]
+impl spin_compose::ComponentSourceLoader for FileSourceLoader {
+    async fn load_component_source(&self, source: &LockedComponentSource) -> Result<Vec<u8>> {
+        let url = source
+            .content
+            .source
+            .as_deref()
+            .context("component source has no file")?;
+        let path = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.to_file_path().ok())
+            .with_context(|| format!("component source {url:?} is not a file"))?;
+        let wasm = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("failed to read {path:?}"))?;
+        Ok(spin_componentize::componentize_if_necessary(&wasm)?.into_owned())
+    }
+}
+
+/// Loads component and dependency sources from the wasm layers of an image, by digest.
+struct LayerSourceLoader<'a>(HashMap<String, &'a [u8]>);
+
+#[async_trait::async_trait]
+impl spin_compose::ComponentSourceLoader for LayerSourceLoader<'_> {
+    async fn load_component_source(&self, source: &LockedComponentSource) -> Result<Vec<u8>> {
+        let digest = source
+            .content
+            .digest
+            .as_deref()
+            .context("component source has no digest")?;
+        let wasm = self
+            .0
+            .get(digest)
+            .with_context(|| format!("image has no layer {digest}"))?;
+        Ok(spin_componentize::componentize_if_necessary(wasm)?.into_owned())
+    }
+}
+
+/// Returns the locked app of the image, if it contains a Spin application layer.
+pub(crate) fn locked_app_from_layers(layers: &[WasmLayer]) -> Result<Option<LockedApp>> {
+    let Some(layer) = layers.iter().find(|layer| {
+        matches!(layer.config.media_type(), MediaType::Other(name) if name == spin_oci::client::SPIN_APPLICATION_MEDIA_TYPE)
+    }) else {
+        return Ok(None);
+    };
+    LockedApp::from_json(&layer.layer)
+        .with_context(|| {
+            format!(
+                "failed to decode locked app from layer {}",
+                layer.config.digest()
+            )
+        })
+        .map(Some)
+}
+
+/// Maps each wasm layer digest referenced by `locked_app` to the ids of the components using it,
+/// either as their source or as a dependency.
+pub(crate) fn component_ids_by_digest(locked_app: &LockedApp) -> HashMap<String, Vec<String>> {
+    let mut component_ids: HashMap<String, Vec<String>> = HashMap::new();
+    for component in &locked_app.components {
+        if let Some(digest) = &component.source.content.digest {
+            component_ids
+                .entry(digest.clone())
+                .or_default()
+                .push(component.id.clone());
+        }
+        for (name, dependency) in &component.dependencies {
+            if let Some(digest) = &dependency.source.content.digest {
+                component_ids
+                    .entry(digest.clone())
+                    .or_default()
+                    .push(format!("{} (dependency {name})", component.id));
+            }
+        }
+    }
+    component_ids
+}
+
+/// Returns the digests of the wasm layers of components with dependencies, and of their
+/// dependencies.
+///
+/// These layers are left uncompiled by [`Compiler::compile`](containerd_shim_wasm::shim::Compiler),
+/// which caches its output by layer digest, while the same main component layer may be composed
+/// with different dependencies. Their compositions are precompiled into the precompile cache of
+/// the node instead, keyed by [`composition_key`].
+pub(crate) fn composed_layer_digests(locked_app: &LockedApp) -> HashSet<String> {
+    locked_app
+        .components
+        .iter()
+        .filter(|component| !component.dependencies.is_empty())
+        .flat_map(|component| {
+            std::iter::once(&component.source).chain(
+                component
+                    .dependencies
+                    .values()
+                    .map(|dependency| &dependency.source),
+            )
+        })
+        .filter_map(|source| source.content.digest.clone())
+        .collect()
+}
+
+/// Returns the key of the composition of `component` with its dependencies: the SHA-256 hash of
+/// the layer digests of the component and its dependencies, and of how they are composed. Returns
+/// `None` if a source has no digest.
+pub(crate) fn composition_key(component: &LockedComponent) -> Option<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(component.source.content.digest.as_deref()?);
+    // Local paths of the sources differ between containers
+    let mut dependencies = component.dependencies.clone();
+    for dependency in dependencies.values_mut() {
+        dependency.source.content.digest.as_ref()?;
+        dependency.source.content.source = None;
+    }
+    hasher.update(serde_json::to_vec(&dependencies).ok()?);
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+/// Composes `component` with its dependencies, loaded from their local files.
+pub(crate) async fn compose_component(component: &LockedComponent) -> Result<Vec<u8>> {
+    log::info!(
+        "composing component {:?} with its dependencies",
+        component.id
+    );
+    Ok(spin_compose::compose(&FileSourceLoader, component).await?)
+}
+
+/// Composes `component` with its dependencies, loaded from the wasm `layers` of the image.
+pub(crate) async fn compose_component_from_layers(
+    component: &LockedComponent,
+    layers: &[&WasmLayer],
+) -> Result<Vec<u8>> {
+    log::info!(
+        "composing component {:?} with its dependencies",
+        component.id
+    );
+    let loader = LayerSourceLoader(
+        layers
+            .iter()
+            .map(|layer| (layer.config.digest().to_string(), layer.layer.as_slice()))
+            .collect(),
+    );
+    Ok(spin_compose::compose(&loader, component).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr as _;
+
+    use oci_spec::image::{Descriptor, Digest};
+
+    use super::*;
+
+    const TEST_DIGEST: &str =
+        "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+
+    fn make_layer(media_type: &str, data: Vec<u8>) -> WasmLayer {
+        WasmLayer {
+            layer: data,
+            config: Descriptor::new(
+                MediaType::Other(media_type.to_string()),
+                1024,
+                Digest::from_str(TEST_DIGEST).unwrap(),
+            ),
+        }
+    }
+
+    #[test]
+    fn locked_app_from_layers_without_app_layer() {
+        let layers = vec![make_layer(
+            crate::constants::OCI_LAYER_MEDIA_TYPE_WASM,
+            vec![],
+        )];
+        assert!(locked_app_from_layers(&layers).unwrap().is_none());
+    }
+
+    #[test]
+    fn locked_app_from_layers_malformed_names_layer() {
+        let layers = vec![make_layer(
+            spin_oci::client::SPIN_APPLICATION_MEDIA_TYPE,
+            b"not json".to_vec(),
+        )];
+        let err = locked_app_from_layers(&layers).unwrap_err().to_string();
+        assert!(err.contains(TEST_DIGEST), "unexpected error message: {err}");
+    }
+
+    #[test]
+    fn component_ids_are_mapped_to_layer_digests() {
+        let app_json = format!(
+            r#"{{
+                "spin_lock_version": 1,
+                "components": [
+                    {{ "id": "a", "source": {{ "content_type": "application/wasm", "content": {{ "digest": "{TEST_DIGEST}" }} }} }},
+                    {{ "id": "b", "source": {{ "content_type": "application/wasm", "content": {{ "digest": "{TEST_DIGEST}" }} }} }},
+                    {{ "id": "c", "source": {{ "content_type": "application/wasm", "content": {{}} }} }}
+                ],
+                "triggers": []
+            }}"#
+        );
+        let locked_app = LockedApp::from_json(app_json.as_bytes()).unwrap();
+        let component_ids = component_ids_by_digest(&locked_app);
+        assert_eq!(component_ids.len(), 1);
+        assert_eq!(component_ids[TEST_DIGEST], vec!["a", "b"]);
+    }
+
+    #[test]
+    fn composed_layer_digests_include_dependencies() {
+        const DEPENDENCY_DIGEST: &str =
+            "sha256:0000000000000000000000000000000000000000000000000000000000000001";
+        const STANDALONE_DIGEST: &str =
+            "sha256:0000000000000000000000000000000000000000000000000000000000000002";
+        let app_json = format!(
+            r#"{{
+                "spin_lock_version": 1,
+                "components": [
+                    {{
+                        "id": "composed",
+                        "source": {{ "content_type": "application/wasm", "content": {{ "digest": "{TEST_DIGEST}" }} }},
+                        "dependencies": {{
+                            "test:dep/iface": {{
+                                "source": {{ "content_type": "application/wasm", "content": {{ "digest": "{DEPENDENCY_DIGEST}" }} }}
+                            }}
+                        }}
+                    }},
+                    {{ "id": "standalone", "source": {{ "content_type": "application/wasm", "content": {{ "digest": "{STANDALONE_DIGEST}" }} }} }}
+                ],
+                "triggers": []
+            }}"#
+        );
+        let locked_app = LockedApp::from_json(app_json.as_bytes()).unwrap();
+        let digests = composed_layer_digests(&locked_app);
+        assert_eq!(
+            digests,
+            HashSet::from([TEST_DIGEST.to_string(), DEPENDENCY_DIGEST.to_string()])
+        );
+    }
+}
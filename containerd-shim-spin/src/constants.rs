@@ -10,16 +10,142 @@ pub(crate) const SPIN_HTTP_LISTEN_ADDR_ENV: &str = "SPIN_HTTP_LISTEN_ADDR";
 pub(crate) const RUNTIME_CONFIG_PATH: &str = "/runtime-config.toml";
 /// Describes an OCI layer with Wasm content
 pub(crate) const OCI_LAYER_MEDIA_TYPE_WASM: &str = "application/vnd.wasm.content.layer.v1+wasm";
+/// Annotation on a precompiled Wasm layer naming the digest of the Wasm layer
+/// it was compiled from. If the precompiled layer is not compatible with the
+/// shim's engine, it is recompiled from that layer.
+pub(crate) const OCI_ANNOTATION_PRECOMPILED_SOURCE: &str =
+    "dev.spinframework.wasm.precompiled.source";
+/// Annotation on a precompiled Wasm layer naming the wasmtime version and target
+/// that produced it, as `wasmtime-<version>/<arch>-<os>`, e.g.
+/// `wasmtime-42.0.2/x86_64-linux`, compared with the shim's engine instead of
+/// deserializing the layer.
+pub(crate) const OCI_ANNOTATION_PRECOMPILED_ENGINE: &str =
+    "dev.spinframework.wasm.precompiled.engine";
+/// Version of wasmtime the shim is built with, which must match the `wasmtime`
+/// dependency in Cargo.toml
+pub(crate) const WASMTIME_VERSION: &str = "42.0.2";
+/// Media type of the layer holding the signature of a Spin application image,
+/// see [`crate::signature::TrustPolicy`]
+pub(crate) const OCI_LAYER_MEDIA_TYPE_SIGNATURE: &str =
+    "application/vnd.dev.spinframework.signature.v1+json";
+// Media types for gzip and zstd compressed wasm and data layers. Wasm layers are
+// decompressed before they are cached or precompiled, and data layers are
+// decompressed as they are written to disk
+pub(crate) const OCI_LAYER_MEDIA_TYPE_WASM_GZIP: &str =
+    "application/vnd.wasm.content.layer.v1+wasm+gzip";
+pub(crate) const OCI_LAYER_MEDIA_TYPE_WASM_ZSTD: &str =
+    "application/vnd.wasm.content.layer.v1+wasm+zstd";
+pub(crate) const OCI_LAYER_MEDIA_TYPE_DATA_GZIP: &str =
+    "application/vnd.wasm.content.layer.v1+data+gzip";
+pub(crate) const OCI_LAYER_MEDIA_TYPE_DATA_ZSTD: &str =
+    "application/vnd.wasm.content.layer.v1+data+zstd";
+/// Default maximum size in bytes of the decompressed content of a compressed
+/// layer, see [`crate::node_config::NodeConfig`]
+pub(crate) const MAX_DECOMPRESSED_LAYER_SIZE_DEFAULT: u64 = 1024 * 1024 * 1024;
 // Media type for a Wasm binary pushed by wkg
 pub(crate) const OCI_LAYER_MEDIA_TYPE_WASM_WKG: &str = "application/wasm";
-/// Expected location of the Spin manifest when loading from a file rather than
+/// Default location of the Spin manifest when loading from a file rather than
 /// an OCI image
 pub(crate) const SPIN_MANIFEST_FILE_PATH: &str = "/spin.toml";
+/// File name of the Spin manifest looked up in a directory entrypoint
+pub(crate) const SPIN_MANIFEST_FILE_NAME: &str = "spin.toml";
+/// Path of the Spin manifest of a file-based application. Takes precedence over
+/// the container entrypoint; a directory is searched for `spin.toml`
+pub(crate) const SPIN_MANIFEST_PATH_ENV: &str = "SPIN_MANIFEST_PATH";
 /// Known prefix for the Spin application variables environment variable
 /// provider: https://github.com/fermyon/spin/blob/436ad589237c02f7aa4693e984132808fd80b863/crates/variables/src/provider/env.rs#L9
 pub(crate) const SPIN_APPLICATION_VARIABLE_PREFIX: &str = "SPIN_VARIABLE";
 /// Working directory for Spin applications
 pub(crate) const SPIN_TRIGGER_WORKING_DIR: &str = "/";
+/// Writable directory the shim keeps its own state in: the content cache,
+/// temporary files, precompiled components, copied files and the application
+/// state directory. Set to an `emptyDir` mount to run with a read-only root
+/// filesystem.
+pub(crate) const SPIN_SCRATCH_DIR_ENV: &str = "SPIN_SCRATCH_DIR";
+/// Default scratch directory, the root of the container
+pub(crate) const SPIN_SCRATCH_DIR_DEFAULT: &str = "/";
+/// How files of file-based applications are mounted into components: `direct`
+/// (the default) mounts the files of the container, while `copy` copies them
+/// into a directory of the application under [`SPIN_FILES_MOUNT_DIR_ENV`]
+/// first, emptied on every start. Each reload copies them into a directory of
+/// its own.
+pub(crate) const SPIN_FILES_MOUNT_STRATEGY_ENV: &str = "SPIN_FILES_MOUNT_STRATEGY";
+/// Scratch directory into which files are copied with the `copy` files mount
+/// strategy.
+pub(crate) const SPIN_FILES_MOUNT_DIR_ENV: &str = "SPIN_FILES_MOUNT_DIR";
+/// Default scratch directory for the `copy` files mount strategy, relative to
+/// the shim's scratch directory
+pub(crate) const SPIN_FILES_MOUNT_DIR_DEFAULT: &str = ".spin/files";
+/// Set to `true` to reload file-based applications when their manifest or the
+/// sources and files of their components change, for local development with a
+/// bind-mounted application directory. The previous version keeps running until
+/// the new one is built, and keeps running if building it fails. HTTP requests
+/// already accepted by the previous version finish on it, while the other
+/// triggers stop immediately, abandoning the messages they were processing.
+pub(crate) const SPIN_DEV_WATCH_ENV: &str = "SPIN_DEV_WATCH";
+/// Set to `true` to mount files read-only into every component, overriding the
+/// `allow_transient_write` trigger option, so that guests cannot write back into
+/// image content.
+pub(crate) const SPIN_FILES_READ_ONLY_ENV: &str = "SPIN_FILES_READ_ONLY";
+/// Set to `false` to write every data and archive layer of an OCI application
+/// to the cache at startup. By default they are only indexed at startup, and
+/// the files of a component are written when they are first mounted, so that
+/// startup does not depend on the size of the application's assets.
+pub(crate) const SPIN_LAZY_DATA_LAYERS_ENV: &str = "SPIN_LAZY_DATA_LAYERS";
 /// Defines the subset of application components that should be executable by the shim
 /// If empty or DNE, all components will be supported
 pub(crate) const SPIN_COMPONENTS_TO_RETAIN_ENV: &str = "SPIN_COMPONENTS_TO_RETAIN";
+/// Set to `true` to skip checking, at startup, that the imports of all
+/// application components are provided by the shim and that trigger
+/// components export the interfaces their triggers call
+pub(crate) const SPIN_SKIP_IMPORT_CHECK_ENV: &str = "SPIN_SKIP_IMPORT_CHECK";
+/// Maximum number of wasm layers precompiled concurrently. Defaults to the
+/// number of available CPUs.
+pub(crate) const SPIN_PRECOMPILE_PARALLELISM_ENV: &str = "SPIN_PRECOMPILE_PARALLELISM";
+/// Node directory in which the shim precompiles the components of file-based
+/// applications and composed components, shared by the containers of the node.
+/// Precompiled components are loaded as native code, so artifacts are only
+/// loaded if recorded as precompiled by the shim, see [`crate::precompiled`].
+pub(crate) const SPIN_PRECOMPILE_CACHE_DIR: &str = "/var/lib/containerd-shim-spin/cache";
+/// Directory in which components are precompiled when the node directory
+/// [`SPIN_PRECOMPILE_CACHE_DIR`] cannot be created, relative to the shim's
+/// scratch directory
+pub(crate) const SPIN_PRECOMPILE_SCRATCH_CACHE_DIR: &str = ".cache/precompiled";
+/// Content cache directory shared between the containers of a node, e.g. a
+/// `hostPath` volume mounted into every pod. Layers are written to it
+/// atomically and only once per node. Each container uses its own cache under
+/// the scratch directory unless this is set.
+pub(crate) const SPIN_SHARED_CACHE_DIR_ENV: &str = "SPIN_SHARED_CACHE_DIR";
+/// Maximum size in bytes of the shared content cache. Once exceeded, the least
+/// recently used content that no running application references is removed.
+/// The cache grows without bound unless this is set.
+pub(crate) const SPIN_SHARED_CACHE_MAX_SIZE_ENV: &str = "SPIN_SHARED_CACHE_MAX_SIZE";
+/// Node directory in which shim processes record the artifacts they precompiled,
+/// see [`crate::precompiled::load`]
+pub(crate) const SPIN_PRECOMPILED_RECORDS_DIR: &str = "/var/lib/containerd-shim-spin/precompiled";
+/// Path of the node config of the shim, see [`crate::node_config::NodeConfig`]
+pub(crate) const SPIN_NODE_CONFIG_PATH_DEFAULT: &str = "/etc/containerd-shim-spin/config.json";
+/// Overrides the path of the node config in the environment of the shim
+/// process. Containers do not read it, as they run with the environment of
+/// their pod.
+pub(crate) const SPIN_NODE_CONFIG_PATH_ENV: &str = "SPIN_NODE_CONFIG_PATH";
+/// Default export run to pre-initialize a module, see [`crate::preinit::Preinitializer`]
+pub(crate) const SPIN_PRECOMPILE_PREINIT_MODULE_FUNC_DEFAULT: &str = "wizer.initialize";
+/// Default export run to pre-initialize a component. Component export names
+/// cannot contain `.`, so it differs from the module default.
+pub(crate) const SPIN_PRECOMPILE_PREINIT_COMPONENT_FUNC_DEFAULT: &str = "wizer-initialize";
+/// Default fuel available to the export run to pre-initialize wasm
+pub(crate) const SPIN_PRECOMPILE_PREINIT_FUEL_DEFAULT: u64 = 10_000_000_000;
+/// Default maximum size in bytes of each linear memory while pre-initializing wasm
+pub(crate) const SPIN_PRECOMPILE_PREINIT_MAX_MEMORY_DEFAULT: usize = 512 * 1024 * 1024;
+/// HTTP route of the component of a wasm package, e.g. `/api/...`. Defaults
+/// to the route Spin gives bare components.
+pub(crate) const SPIN_WKG_HTTP_ROUTE_ENV: &str = "SPIN_WKG_HTTP_ROUTE";
+/// Comma separated `allowed_outbound_hosts` of the component of a wasm package
+pub(crate) const SPIN_WKG_ALLOWED_OUTBOUND_HOSTS_ENV: &str = "SPIN_WKG_ALLOWED_OUTBOUND_HOSTS";
+/// Comma separated key-value stores the component of a wasm package may use
+pub(crate) const SPIN_WKG_KEY_VALUE_STORES_ENV: &str = "SPIN_WKG_KEY_VALUE_STORES";
+/// Comma separated application variables of a wasm package, each `name` or
+/// `name=default`, exposed to its component as config of the same name. Values
+/// are set like those of other applications, through the container environment.
+pub(crate) const SPIN_WKG_VARIABLES_ENV: &str = "SPIN_WKG_VARIABLES";
@@ -0,0 +1,587 @@
+use std::{
+    collections::HashSet,
+    env,
+    fs::{self, DirBuilder, File},
+    io::{self, Read as _, Write as _},
+    os::unix::fs::{DirBuilderExt as _, MetadataExt as _},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use sha2::{Digest as _, Sha256};
+use spin_app::locked::{LockedApp, LockedComponentSource};
+use spin_loader::cache::Cache;
+use tempfile::NamedTempFile;
+
+use crate::{constants, precompiled};
+
+/// Content used within this period is never collected, so that content written by a container
+/// that has not recorded its references yet is not removed from under it.
+const GC_GRACE_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+/// Period at which running apps mark their references as fresh.
+const REFERENCES_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// References not marked as fresh within this period belong to containers that did not exit
+/// cleanly, such as killed ones, and are removed.
+const REFERENCES_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Writes a wasm layer to the cache, unless the cache already holds it.
+pub(crate) fn write_wasm(cache: &Cache, bytes: &[u8], digest: impl AsRef<str>) -> Result<()> {
+    let path = cache.wasm_path(digest.as_ref());
+    log_lookup("wasm", digest.as_ref(), write_atomically(&path, bytes)?);
+    Ok(())
+}
+
+/// Returns the directory in which `cache` holds data layers.
+pub(crate) fn data_dir(cache: &Cache) -> Result<PathBuf> {
+    parent_dir(&cache.data_path("_")).map(Path::to_path_buf)
+}
+
+/// Writes a data layer to the data directory of the cache, unless it already holds it intact. The
+/// content is streamed by `write` into a temporary file renamed into place.
+pub(crate) fn write_data(
+    data_dir: &Path,
+    digest: &str,
+    write: impl FnOnce(&mut dyn io::Write) -> Result<()>,
+) -> Result<()> {
+    let path = data_dir.join(digest);
+    if is_intact(&path, digest) {
+        touch(&path)?;
+        log_lookup("data", digest, false);
+        return Ok(());
+    }
+    let mut file = NamedTempFile::new_in(data_dir)
+        .with_context(|| format!("failed to create temporary file for {path:?}"))?;
+    let mut writer = io::BufWriter::new(file.as_file_mut());
+    write(&mut writer)?;
+    writer
+        .flush()
+        .with_context(|| format!("failed to write {path:?}"))?;
+    drop(writer);
+    file.persist(&path)
+        .with_context(|| format!("failed to move content to {path:?}"))?;
+    log_lookup("data", digest, true);
+    Ok(())
+}
+
+/// Unpacks a gzipped tar archive layer into the data directory of the cache, writing each file
+/// atomically under the digest of its content as Spin does. Archives whose unpacked files are all
+/// still in the cache are not unpacked again.
+pub(crate) fn unpack_archive(data_dir: &Path, archive: impl io::Read, digest: &str) -> Result<()> {
+    // Lists the files unpacked from the archive with their sizes, written once all are in place
+    let listing_path = parent_dir(data_dir)?.join("unpacked").join(digest);
+    if let Some(files) = unpacked_files(&listing_path, data_dir) {
+        for file in files {
+            touch(&file)?;
+        }
+        log_lookup("archive", digest, false);
+        return Ok(());
+    }
+
+    let mut listing = String::new();
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(archive));
+    for entry in archive
+        .entries()
+        .with_context(|| format!("failed to read archive layer {digest}"))?
+    {
+        let mut entry = entry.with_context(|| format!("failed to read archive layer {digest}"))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let mut content = Vec::new();
+        entry
+            .read_to_end(&mut content)
+            .with_context(|| format!("failed to read archive layer {digest}"))?;
+        let file_digest = format!("sha256:{:x}", Sha256::digest(&content));
+        write_atomically(&data_dir.join(&file_digest), &content)?;
+        listing.push_str(&format!("{} {file_digest}\n", content.len()));
+    }
+    fs::create_dir_all(parent_dir(&listing_path)?)?;
+    write_atomically(&listing_path, listing.as_bytes())?;
+    log_lookup("archive", digest, true);
+    Ok(())
+}
+
+// Returns the paths of the files unpacked from an archive, if its listing exists and all of them
+// are still intact in the cache. Files are named by the digest of their content.
+fn unpacked_files(listing_path: &Path, data_dir: &Path) -> Option<Vec<PathBuf>> {
+    fs::read_to_string(listing_path)
+        .ok()?
+        .lines()
+        .map(|line| {
+            let (size, name) = line.split_once(' ')?;
+            let path = data_dir.join(name);
+            (is_cached(&path, size.parse().ok()?) && is_intact(&path, name)).then_some(path)
+        })
+        .collect()
+}
+
+/// Returns whether the file at `path` hashes to `digest`.
+pub(crate) fn is_intact(path: &Path, digest: &str) -> bool {
+    let Some(expected) = digest.strip_prefix("sha256:") else {
+        return false;
+    };
+    let mut hasher = Sha256::new();
+    File::open(path)
+        .and_then(|mut file| io::copy(&mut file, &mut hasher))
+        .is_ok_and(|_| format!("{:x}", hasher.finalize()) == expected)
+}
+
+/// Checks that the cached source of a component was not tampered with: its content hashes to the
+/// digest of its layer, or was precompiled by the shim from the layer. Precompiled content that
+/// hashes to the digest of its layer is only accepted if the node config trusts precompiled layers
+/// of images.
+pub(crate) fn verify_source(source: &LockedComponentSource) -> Result<()> {
+    let (Some(digest), Some(path)) = (
+        source.content.digest.as_deref(),
+        source
+            .content
+            .source
+            .as_deref()
+            .and_then(|source| url::Url::parse(source).ok()?.to_file_path().ok()),
+    ) else {
+        return Ok(());
+    };
+    verify_wasm(&path, digest)
+}
+
+/// Checks that the cached wasm layer at `path` hashes to `digest`, or was precompiled by the shim
+/// from the layer, see [`verify_source`].
+pub(crate) fn verify_wasm(path: &Path, digest: &str) -> Result<()> {
+    let wasm = fs::read(path).with_context(|| format!("failed to read cached wasm {path:?}"))?;
+    if wasmtime::Engine::detect_precompiled(&wasm).is_some() {
+        let trusted = precompiled::is_recorded(&wasm, digest)
+            || (precompiled::trust_image_precompiled()
+                && format!("sha256:{:x}", Sha256::digest(&wasm)) == digest);
+        ensure!(
+            trusted,
+            "cached wasm {path:?} is precompiled content that was not precompiled by the shim from layer {digest}"
+        );
+        return Ok(());
+    }
+    ensure!(
+        format!("sha256:{:x}", Sha256::digest(&wasm)) == digest,
+        "cached wasm {path:?} does not match layer {digest}"
+    );
+    Ok(())
+}
+
+fn log_lookup(kind: &str, digest: &str, written: bool) {
+    if written {
+        log::info!("<<< cache miss for {kind} layer {digest}, written to cache");
+    } else {
+        log::info!("<<< cache hit for {kind} layer {digest}, skipping write");
+    }
+}
+
+// Writes `bytes` to `path` through a temporary file renamed into place, so that containers
+// sharing the cache never read partially written content. Returns whether `path` was written:
+// content already in the cache, checked to be intact, is only marked as recently used.
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<bool> {
+    if is_cached(path, bytes.len() as u64) && fs::read(path).is_ok_and(|cached| cached == bytes) {
+        touch(path)?;
+        return Ok(false);
+    }
+    let mut file = NamedTempFile::new_in(parent_dir(path)?)
+        .with_context(|| format!("failed to create temporary file for {path:?}"))?;
+    file.write_all(bytes)
+        .with_context(|| format!("failed to write {path:?}"))?;
+    file.persist(path)
+        .with_context(|| format!("failed to move content to {path:?}"))?;
+    Ok(true)
+}
+
+fn is_cached(path: &Path, size: u64) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.len() == size)
+}
+
+fn touch(path: &Path) -> Result<()> {
+    File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()))
+        .with_context(|| format!("failed to mark {path:?} as used"))
+}
+
+fn parent_dir(path: &Path) -> Result<&Path> {
+    path.parent()
+        .with_context(|| format!("cache path {path:?} has no parent"))
+}
+
+/// Content cache shared between the containers of a node, configured by
+/// [`constants::SPIN_SHARED_CACHE_DIR_ENV`].
+///
+/// Cached wasm is loaded as native code once precompiled, so the cache directory must only be
+/// writable by the user the shim runs as, and cached content is checked against its digest
+/// before use. Pods running as that user with the directory mounted can still write to it.
+///
+/// Every running app records the cache content it uses in a reference file, kept fresh while the
+/// app runs and removed when it exits. When the cache outgrows
+/// [`constants::SPIN_SHARED_CACHE_MAX_SIZE_ENV`], the least recently used content that no app
+/// references is collected. References of containers that did not exit cleanly expire after
+/// [`REFERENCES_TTL`].
+#[derive(Clone, Debug)]
+pub(crate) struct SharedCache {
+    root: PathBuf,
+    max_size: Option<u64>,
+}
+
+impl SharedCache {
+    /// Returns the shared cache, or `None` if containers each use their own cache.
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        let Some(root) =
+            env::var_os(constants::SPIN_SHARED_CACHE_DIR_ENV).filter(|root| !root.is_empty())
+        else {
+            return Ok(None);
+        };
+        let max_size = env::var(constants::SPIN_SHARED_CACHE_MAX_SIZE_ENV)
+            .ok()
+            .filter(|size| !size.is_empty())
+            .map(|size| {
+                size.parse().with_context(|| {
+                    format!(
+                        "invalid {} value {size:?}, expected a size in bytes",
+                        constants::SPIN_SHARED_CACHE_MAX_SIZE_ENV
+                    )
+                })
+            })
+            .transpose()?;
+        Self::new(root.into(), max_size).map(Some)
+    }
+
+    fn new(root: PathBuf, max_size: Option<u64>) -> Result<Self> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&root)
+            .with_context(|| format!("failed to create shared cache dir {root:?}"))?;
+        let metadata = fs::metadata(&root)
+            .with_context(|| format!("failed to read shared cache dir {root:?}"))?;
+        // SAFETY: geteuid has no preconditions and cannot fail
+        let uid = unsafe { libc::geteuid() };
+        if metadata.uid() != uid || metadata.mode() & 0o022 != 0 {
+            bail!(
+                "shared cache dir {root:?} must be owned by uid {uid} and not writable by group or others, as cached content is executed by the shim"
+            );
+        }
+        Ok(Self { root, max_size })
+    }
+
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Records the cache content used by `locked_app`, until the returned references are dropped.
+    pub(crate) fn record_references(
+        &self,
+        app_id: &str,
+        locked_app: &LockedApp,
+    ) -> Result<References> {
+        let refs_dir = self.root.join("refs");
+        fs::create_dir_all(&refs_dir)
+            .with_context(|| format!("failed to create cache refs dir {refs_dir:?}"))?;
+        let mut references = tempfile::Builder::new()
+            .prefix(&format!("{app_id}-"))
+            .tempfile_in(&refs_dir)
+            .context("failed to create cache references")?;
+        for path in content_paths(locked_app) {
+            writeln!(references, "{}", path.display())?;
+        }
+        references.flush()?;
+
+        let path = references.path().to_path_buf();
+        let heartbeat = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(REFERENCES_HEARTBEAT_INTERVAL).await;
+                if let Err(err) = touch(&path) {
+                    log::warn!("failed to refresh cache references: {err:?}");
+                }
+            }
+        });
+        Ok(References {
+            _file: references,
+            heartbeat,
+        })
+    }
+
+    /// Removes the least recently used unreferenced content of the wasm and data directories of
+    /// `cache` until the cache fits its maximum size.
+    pub(crate) fn collect_garbage(&self, cache: &Cache) -> Result<()> {
+        let Some(max_size) = self.max_size else {
+            return Ok(());
+        };
+        let referenced = self.referenced_paths()?;
+        let mut content = Vec::new();
+        for dir in [cache.wasm_path("_"), cache.data_path("_")] {
+            for entry in fs::read_dir(parent_dir(&dir)?)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_file() {
+                    content.push((entry.path(), metadata.len(), metadata.modified()?));
+                }
+            }
+        }
+        let mut size: u64 = content.iter().map(|(_, len, _)| len).sum();
+        if size <= max_size {
+            return Ok(());
+        }
+
+        content.sort_by_key(|(_, _, modified)| *modified);
+        let now = SystemTime::now();
+        for (path, len, modified) in content {
+            if size <= max_size {
+                break;
+            }
+            let recently_used = now
+                .duration_since(modified)
+                .map_or(true, |age| age < GC_GRACE_PERIOD);
+            if recently_used || referenced.contains(&path) {
+                continue;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    log::info!("<<< collected unused cache content {path:?} ({len} bytes)");
+                    size -= len;
+                }
+                Err(err) => log::warn!("failed to collect cache content {path:?}: {err}"),
+            }
+        }
+        if size > max_size {
+            log::warn!(
+                "shared cache holds {size} bytes of content in use, over its maximum of {max_size} bytes"
+            );
+        }
+        Ok(())
+    }
+
+    // Returns the content paths referenced by any running app, removing expired references
+    fn referenced_paths(&self) -> Result<HashSet<PathBuf>> {
+        let mut referenced = HashSet::new();
+        let Ok(entries) = fs::read_dir(self.root.join("refs")) else {
+            return Ok(referenced);
+        };
+        let now = SystemTime::now();
+        for entry in entries {
+            let path = entry?.path();
+            // References are removed concurrently by the apps exiting
+            let Ok(modified) = fs::metadata(&path).and_then(|metadata| metadata.modified()) else {
+                continue;
+            };
+            if now
+                .duration_since(modified)
+                .is_ok_and(|age| age > REFERENCES_TTL)
+            {
+                match fs::remove_file(&path) {
+                    Ok(()) => log::info!("<<< removed expired cache references {path:?}"),
+                    Err(err) => {
+                        log::warn!("failed to remove expired cache references {path:?}: {err}")
+                    }
+                }
+                continue;
+            }
+            let Ok(references) = fs::read_to_string(&path) else {
+                continue;
+            };
+            referenced.extend(references.lines().map(PathBuf::from));
+        }
+        Ok(referenced)
+    }
+}
+
+/// References of a running app to shared cache content, see [`SharedCache::record_references`].
+pub(crate) struct References {
+    _file: NamedTempFile,
+    heartbeat: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for References {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+    }
+}
+
+// Returns the local paths of the sources and files of the components of `locked_app`
+pub(crate) fn content_paths(locked_app: &LockedApp) -> impl Iterator<Item = PathBuf> + '_ {
+    locked_app
+        .components
+        .iter()
+        .flat_map(|component| {
+            std::iter::once(&component.source.content)
+                .chain(
+                    component
+                        .dependencies
+                        .values()
+                        .map(|dependency| &dependency.source.content),
+                )
+                .chain(component.files.iter().map(|file| &file.content))
+        })
+        .filter_map(|content| content.source.as_deref())
+        .filter_map(|source| url::Url::parse(source).ok()?.to_file_path().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn make_cache(root: &Path) -> Cache {
+        Cache::new(Some(root.to_path_buf())).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn content_is_written_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = make_cache(dir.path()).await;
+        let digest = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+
+        write_wasm(&cache, b"wasm", digest).unwrap();
+        let path = cache.wasm_path(digest);
+        let written = fs::metadata(&path).unwrap().modified().unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(written - Duration::from_secs(60))
+            .unwrap();
+
+        assert!(!write_atomically(&path, b"wasm").unwrap());
+        assert_eq!(fs::read(&path).unwrap(), b"wasm");
+        assert!(fs::metadata(&path).unwrap().modified().unwrap() >= written);
+
+        // Corrupted content is written again
+        fs::write(&path, b"wasn").unwrap();
+        assert!(write_atomically(&path, b"wasm").unwrap());
+        assert_eq!(fs::read(&path).unwrap(), b"wasm");
+    }
+
+    #[test]
+    fn archive_is_unpacked_again_when_files_are_tampered_with() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("data");
+        fs::create_dir_all(&data_dir).unwrap();
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "index.html", b"index".as_slice())
+            .unwrap();
+        let archive = builder.into_inner().unwrap().finish().unwrap();
+        let file = data_dir.join(format!("sha256:{:x}", Sha256::digest(b"index")));
+
+        unpack_archive(&data_dir, archive.as_slice(), "sha256:archive").unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"index");
+
+        // Content of the same size is not mistaken for the unpacked file
+        fs::write(&file, b"xxxxx").unwrap();
+        unpack_archive(&data_dir, archive.as_slice(), "sha256:archive").unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"index");
+    }
+
+    #[tokio::test]
+    async fn unreferenced_content_is_collected() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = make_cache(dir.path()).await;
+        let shared_cache = SharedCache::new(dir.path().to_path_buf(), Some(4)).unwrap();
+        let used = format!("sha256:{:x}", Sha256::digest(b"used"));
+        let unused = format!("sha256:{:x}", Sha256::digest(b"unused"));
+        let old = SystemTime::now() - 2 * GC_GRACE_PERIOD;
+        for (digest, content) in [(&used, b"used".as_slice()), (&unused, b"unused")] {
+            write_wasm(&cache, content, digest).unwrap();
+            File::options()
+                .write(true)
+                .open(cache.wasm_path(digest))
+                .unwrap()
+                .set_modified(old)
+                .unwrap();
+        }
+
+        let source = url::Url::from_file_path(cache.wasm_path(&used)).unwrap();
+        let locked_app = LockedApp::from_json(
+            format!(
+                r#"{{
+                    "spin_lock_version": 1,
+                    "components": [{{
+                        "id": "hello",
+                        "source": {{ "content_type": "application/wasm", "content": {{ "source": "{source}" }} }}
+                    }}],
+                    "triggers": []
+                }}"#
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let references = shared_cache
+            .record_references("hello", &locked_app)
+            .unwrap();
+
+        shared_cache.collect_garbage(&cache).unwrap();
+        assert!(cache.wasm_path(&used).exists());
+        assert!(!cache.wasm_path(&unused).exists());
+
+        drop(references);
+        assert!(shared_cache.referenced_paths().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_references_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared_cache = SharedCache::new(dir.path().to_path_buf(), None).unwrap();
+        let locked_app = LockedApp::from_json(
+            br#"{ "spin_lock_version": 1, "components": [], "triggers": [] }"#,
+        )
+        .unwrap();
+        let references = shared_cache
+            .record_references("killed", &locked_app)
+            .unwrap();
+        let path = references._file.path().to_path_buf();
+        fs::write(&path, "/cache/wasm/killed\n").unwrap();
+        assert_eq!(shared_cache.referenced_paths().unwrap().len(), 1);
+
+        // A container killed before dropping its references stops refreshing them
+        std::mem::forget(references);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - 2 * REFERENCES_TTL)
+            .unwrap();
+        assert!(shared_cache.referenced_paths().unwrap().is_empty());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn shared_cache_dir_must_be_private() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("cache");
+        SharedCache::new(root.clone(), None).unwrap();
+        assert_eq!(fs::metadata(&root).unwrap().mode() & 0o777, 0o700);
+
+        fs::set_permissions(&root, fs::Permissions::from_mode(0o777)).unwrap();
+        let err = SharedCache::new(root, None).unwrap_err().to_string();
+        assert!(
+            err.contains("not writable"),
+            "unexpected error message: {err}"
+        );
+    }
+
+    #[test]
+    fn tampered_wasm_is_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wasm");
+        let digest = format!("sha256:{:x}", Sha256::digest(b"wasm"));
+        fs::write(&path, b"wasm").unwrap();
+        assert!(is_intact(&path, &digest));
+        verify_wasm(&path, &digest).unwrap();
+
+        fs::write(&path, b"wasn").unwrap();
+        assert!(!is_intact(&path, &digest));
+        assert!(verify_wasm(&path, &digest).is_err());
+    }
+}
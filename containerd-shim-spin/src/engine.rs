@@ -1,6 +1,15 @@
-use std::{collections::HashSet, env, hash::Hash};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    future::Future,
+    hash::Hash,
+    path::{Path, PathBuf},
+    pin::pin,
+    sync::Arc,
+    time::Instant,
+};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use containerd_shim_wasm::{
     sandbox::{
         context::{RuntimeContext, WasmLayer},
@@ -8,32 +17,55 @@ use containerd_shim_wasm::{
     },
     shim::{version, Compiler, Shim, Version},
 };
-use futures::future;
+use futures::{
+    future::{self, BoxFuture, Either},
+    stream, StreamExt, TryStreamExt,
+};
 use log::info;
-use spin_app::locked::LockedApp;
+use sha2::{Digest as _, Sha256};
+use spin_app::locked::{LockedApp, LockedComponent};
 use spin_factor_outbound_networking::validate_service_chaining_for_components;
+use spin_loader::cache::Cache;
 use spin_trigger::cli::NoCliArgs;
 use spin_trigger_http::HttpTrigger;
 use spin_trigger_redis::RedisTrigger;
+use tempfile::TempDir;
 use trigger_command::CommandTrigger;
 use trigger_mqtt::MqttTrigger;
 use trigger_sqs::SqsTrigger;
 
 use crate::{
-    constants,
+    capabilities, compose, constants,
+    content_cache::SharedCache,
+    engine_options::EngineOptions,
+    lazy_files::LazyFiles,
+    node_config, precompiled,
+    preinit::Preinitializer,
+    signature::{self, TrustPolicy},
     source::Source,
     trigger::{
         self, get_supported_triggers, COMMAND_TRIGGER_TYPE, HTTP_TRIGGER_TYPE, MQTT_TRIGGER_TYPE,
         REDIS_TRIGGER_TYPE, SQS_TRIGGER_TYPE,
     },
     utils::{
-        configure_application_variables_from_environment_variables, initialize_cache,
-        is_wasm_content, parse_addr,
+        app_id, check_scratch_dir_writable,
+        configure_application_variables_from_environment_variables, decompress_wasm_layer,
+        initialize_cache, is_wasm_content, parse_addr, precompile_cache_dir,
+        precompile_parallelism, scratch_dir, sha256_hash, verify_descriptor,
     },
+    watch::{watch_enabled, AppWatcher, Watch},
+    wkg::{self, is_wit_package},
 };
 
 pub struct SpinShim;
-pub struct SpinCompiler(wasmtime::Engine);
+pub struct SpinCompiler(
+    wasmtime::Engine,
+    EngineOptions,
+    Option<Preinitializer>,
+    Option<TrustPolicy>,
+    /// Whether precompiled layers of images are trusted, see [`NodeConfig`](node_config::NodeConfig)
+    bool,
+);
 
 #[derive(Default)]
 pub struct SpinSandbox;
@@ -55,16 +87,22 @@ impl Shim for SpinShim {
             constants::OCI_LAYER_MEDIA_TYPE_WASM_WKG,
             spin_oci::client::ARCHIVE_MEDIATYPE,
             spin_oci::client::DATA_MEDIATYPE,
+            constants::OCI_LAYER_MEDIA_TYPE_WASM_GZIP,
+            constants::OCI_LAYER_MEDIA_TYPE_WASM_ZSTD,
+            constants::OCI_LAYER_MEDIA_TYPE_DATA_GZIP,
+            constants::OCI_LAYER_MEDIA_TYPE_DATA_ZSTD,
             spin_oci::client::SPIN_APPLICATION_MEDIA_TYPE,
+            constants::OCI_LAYER_MEDIA_TYPE_SIGNATURE,
         ]
     }
 
     #[allow(refining_impl_trait)]
     async fn compiler() -> Option<SpinCompiler> {
-        let mut config = spin_core::Config::default();
-        Some(SpinCompiler(
-            wasmtime::Engine::new(config.wasmtime_config()).unwrap(),
-        ))
+        // Without a compiler, components are compiled when loaded, and containers fail to start
+        // on the same error
+        SpinCompiler::new()
+            .inspect_err(|err| log::error!("failed to create precompile engine: {err:?}"))
+            .ok()
     }
 }
 
@@ -112,9 +150,44 @@ impl Sandbox for SpinSandbox {
 
 impl SpinSandbox {
     async fn wasm_exec_async(&self, ctx: &impl RuntimeContext) -> Result<()> {
-        let cache = initialize_cache().await?;
+        check_scratch_dir_writable(&scratch_dir())?;
+        let shared_cache = SharedCache::from_env()?;
+        let cache = initialize_cache(shared_cache.as_ref().map(SharedCache::root)).await?;
         let app_source = Source::from_ctx(ctx, &cache).await?;
-        let mut locked_app = app_source.to_locked_app(&cache).await?;
+        // Engine configured like the one Spin executes components with
+        let compiler = SpinCompiler::new()?;
+        let mut app = self.load_app(&app_source, &cache, &compiler).await?;
+        // Keep the cache content of the app from being collected while it runs
+        let _references = match &shared_cache {
+            Some(shared_cache) => {
+                let references = shared_cache.record_references(&app_id(), &app.locked_app)?;
+                if let Err(err) = shared_cache.collect_garbage(&cache) {
+                    log::warn!("failed to collect unused shared cache content: {err:?}");
+                }
+                Some(references)
+            }
+            None => None,
+        };
+        spin_telemetry::init(version!().version.to_string())?;
+
+        match app.watcher.take() {
+            Some(watcher) => {
+                self.run_watched(ctx, &app_source, &cache, &compiler, app, watcher)
+                    .await
+            }
+            None => self.start_triggers(ctx, app).await?.wait().await,
+        }
+    }
+
+    // Resolves the application of `app_source`, checks that the shim can run it and prepares its
+    // components for loading.
+    async fn load_app(
+        &self,
+        app_source: &Source,
+        cache: &Cache,
+        compiler: &SpinCompiler,
+    ) -> Result<LoadedApp> {
+        let (mut locked_app, lazy_files, files_dir) = app_source.to_locked_app(cache).await?;
         if let Ok(components_env) = env::var(constants::SPIN_COMPONENTS_TO_RETAIN_ENV) {
             let components = components_env
                 .split(',')
@@ -131,43 +204,116 @@ impl SpinSandbox {
                 )
             })?;
         }
+        if let Source::OciWkg(_) = app_source {
+            wkg::configure_world_trigger(&mut locked_app, &compiler.0).await?;
+            wkg::configure_from_env(&mut locked_app)?;
+        }
+        let check_capabilities =
+            !env::var(constants::SPIN_SKIP_IMPORT_CHECK_ENV).is_ok_and(|skip| skip == "true");
+        // Precompiled sources of OCI applications are verified to be produced by the shim or
+        // trusted by the node config, while those of file-based applications are never loaded
+        // as native code
+        let trusted_precompiled = !matches!(app_source, Source::File(_));
+        if check_capabilities {
+            capabilities::check_trigger_exports(&locked_app, &compiler.0, trusted_precompiled)
+                .await?;
+        } else {
+            info!(" >>> skipping component import and export checks");
+        }
+        // Watch the sources of components before they are pointed at precompiled artifacts
+        let watcher = match app_source {
+            Source::File(manifest) if watch_enabled() => {
+                Some(AppWatcher::new(manifest, &locked_app)?)
+            }
+            _ => None,
+        };
+        // Components of OCI applications were compiled by the shim (during `precompile`), except
+        // those composed with their dependencies, which are precompiled into the precompile cache
+        // like the components of file-based applications.
+        let load_aot_compiled = match app_source {
+            Source::OciSpin(..) | Source::OciWkg(_) => {
+                compiler
+                    .precompile_components(&mut locked_app, &precompile_cache_dir())
+                    .await?;
+                true
+            }
+            Source::File(_) => compiler
+                .precompile_file_components(&mut locked_app, &precompile_cache_dir())
+                .await
+                .unwrap_or_else(|err| {
+                    log::warn!("failed to precompile application components: {err:?}");
+                    false
+                }),
+        };
+        // Checked once components are precompiled, which makes loading them for the check cheap
+        if check_capabilities {
+            capabilities::check_component_imports(&locked_app, load_aot_compiled).await?;
+        }
         configure_application_variables_from_environment_variables(&locked_app)?;
-        let trigger_cmds = get_supported_triggers(&locked_app)
+        let trigger_types = get_supported_triggers(&locked_app)
             .with_context(|| format!("Couldn't find trigger executor for {app_source:?}"))?;
-        spin_telemetry::init(version!().version.to_string())?;
-
-        self.run_trigger(ctx, &trigger_cmds, locked_app, app_source)
-            .await
+        Ok(LoadedApp {
+            locked_app,
+            load_aot_compiled,
+            lazy_files,
+            files_dir,
+            trigger_types,
+            watcher,
+        })
     }
 
-    async fn run_trigger(
+    // Runs the triggers of a file-based application, reloading the application whenever its files
+    // change, see `run_reloading`.
+    async fn run_watched(
         &self,
         ctx: &impl RuntimeContext,
-        trigger_types: &HashSet<String>,
-        app: LockedApp,
-        app_source: Source,
+        app_source: &Source,
+        cache: &Cache,
+        compiler: &SpinCompiler,
+        app: LoadedApp,
+        watcher: AppWatcher,
     ) -> Result<()> {
+        info!(" >>> watching application files for changes");
+        let running = self.start_triggers(ctx, app).await?;
+        run_reloading(running.wait(), watcher, || async move {
+            let mut app = self.load_app(app_source, cache, compiler).await?;
+            let watcher = app.watcher.take();
+            let running = self.start_triggers(ctx, app).await?;
+            Ok((running.wait(), watcher))
+        })
+        .await
+    }
+
+    async fn start_triggers(
+        &self,
+        ctx: &impl RuntimeContext,
+        app: LoadedApp,
+    ) -> Result<RunningTriggers> {
+        let LoadedApp {
+            locked_app,
+            load_aot_compiled,
+            lazy_files,
+            files_dir,
+            trigger_types,
+            ..
+        } = app;
         let mut loader = spin_trigger::loader::ComponentLoader::default();
-        match app_source {
-            Source::OciSpin | Source::OciWkg(_) => unsafe {
-                // Configure the loader to support loading AOT compiled components..
-                // Since all components were compiled by the shim (during `precompile`),
-                // this operation can be considered safe.
+        if load_aot_compiled {
+            // Configure the loader to support loading AOT compiled components..
+            // Since all components were compiled by the shim (during `precompile` or into
+            // the precompile cache), or are precompiled layers of images the node config
+            // trusts, this operation can be considered safe.
+            unsafe {
                 loader.enable_loading_aot_compiled_components();
-            },
-            // Currently, it is only possible to precompile applications distributed using
-            // `spin registry push`
-            Source::File(_) => {}
-        };
+            }
+        }
 
         let mut futures_list = Vec::new();
         let mut trigger_type_map = Vec::new();
-        // The `HOSTNAME` environment variable should contain the fully unique container name
-        let app_id = std::sync::Arc::<str>::from(
-            std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown".into()),
-        );
+        let app_id = std::sync::Arc::<str>::from(app_id());
         for trigger_type in trigger_types.iter() {
-            let app = spin_app::App::new(app_id.clone(), app.clone());
+            let app = spin_app::App::new(app_id.clone(), locked_app.clone());
+            let started_at = Instant::now();
             let f = match trigger_type.as_str() {
                 HTTP_TRIGGER_TYPE => {
                     let address_str = env::var(constants::SPIN_HTTP_LISTEN_ADDR_ENV)
@@ -186,25 +332,42 @@ impl SpinSandbox {
                             std::time::Duration::from_secs(1),
                         ),
                     };
-                    trigger::run::<HttpTrigger>(cli_args, app, &loader).await?
+                    trigger::run::<HttpTrigger>(cli_args, app, &loader, lazy_files.clone()).await
+                }
+                REDIS_TRIGGER_TYPE => {
+                    trigger::run::<RedisTrigger>(NoCliArgs, app, &loader, lazy_files.clone()).await
+                }
+                SQS_TRIGGER_TYPE => {
+                    trigger::run::<SqsTrigger>(NoCliArgs, app, &loader, lazy_files.clone()).await
                 }
-                REDIS_TRIGGER_TYPE => trigger::run::<RedisTrigger>(NoCliArgs, app, &loader).await?,
-                SQS_TRIGGER_TYPE => trigger::run::<SqsTrigger>(NoCliArgs, app, &loader).await?,
                 COMMAND_TRIGGER_TYPE => {
                     let cli_args = trigger_command::CliArgs {
                         guest_args: ctx.args().to_vec(),
                     };
-                    trigger::run::<CommandTrigger>(cli_args, app, &loader).await?
+                    trigger::run::<CommandTrigger>(cli_args, app, &loader, lazy_files.clone()).await
                 }
                 MQTT_TRIGGER_TYPE => {
                     let cli_args = trigger_mqtt::CliArgs { test: false };
-                    trigger::run::<MqttTrigger>(cli_args, app, &loader).await?
+                    trigger::run::<MqttTrigger>(cli_args, app, &loader, lazy_files.clone()).await
                 }
                 _ => {
                     // This should never happen as we check for supported triggers in get_supported_triggers
                     unreachable!()
                 }
             };
+            let elapsed = started_at.elapsed();
+            let f = match f {
+                Ok(f) => {
+                    info!(" >>> trigger type '{trigger_type}' started in {elapsed:?}");
+                    f
+                }
+                Err(err) => {
+                    log::error!(
+                        " >>> trigger type '{trigger_type}' failed to start after {elapsed:?}: {err:?}"
+                    );
+                    return Err(err.context(format!("failed to start '{trigger_type}' trigger")));
+                }
+            };
 
             trigger_type_map.push(trigger_type.clone());
             futures_list.push(f);
@@ -212,11 +375,94 @@ impl SpinSandbox {
 
         info!(" >>> notifying main thread we are about to start");
 
+        Ok(RunningTriggers {
+            futures: futures_list,
+            trigger_types: trigger_type_map,
+            _files_dir: files_dir,
+        })
+    }
+}
+
+// Runs an application version until it exits, replacing it by the version `reload` builds
+// whenever `watcher` reports a change, along with the watcher of that version if any.
+//
+// The running version keeps running while the next one is built, and is only stopped once it is
+// built, so that it keeps running when building fails. Versions are stopped by dropping them:
+// HTTP requests they accepted are served by tasks of their own and finish on the stopped version,
+// while the other triggers abandon the messages they were processing. Versions are only started
+// when first polled, once the previous version is stopped, so that the HTTP trigger of the next
+// version can bind the address of the previous one.
+async fn run_reloading<F, W, R>(
+    running: F,
+    mut watcher: W,
+    mut reload: impl FnMut() -> R,
+) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+    W: Watch,
+    R: Future<Output = Result<(F, Option<W>)>>,
+{
+    let mut running = Box::pin(running);
+    loop {
+        if let Either::Left((result, _)) =
+            future::select(running.as_mut(), pin!(watcher.changed())).await
+        {
+            return result;
+        }
+        info!(" >>> application files changed, reloading");
+        let reloaded = match future::select(running.as_mut(), pin!(reload())).await {
+            Either::Left((result, _)) => return result,
+            Either::Right((reloaded, _)) => reloaded,
+        };
+        match reloaded {
+            Ok((next, next_watcher)) => {
+                info!(" >>> stopping triggers of the previous application version");
+                running.set(next);
+                if let Some(next_watcher) = next_watcher {
+                    watcher = next_watcher;
+                }
+            }
+            Err(err) => log::error!(
+                " >>> failed to reload application, keeping the running version: {err:?}"
+            ),
+        }
+    }
+}
+
+/// An application loaded by [`SpinSandbox::load_app`], ready for its triggers to be started.
+struct LoadedApp {
+    locked_app: LockedApp,
+    load_aot_compiled: bool,
+    lazy_files: Option<Arc<LazyFiles>>,
+    /// Files copied with the `copy` files mount strategy, removed once the app stops
+    files_dir: Option<TempDir>,
+    trigger_types: Vec<String>,
+    watcher: Option<AppWatcher>,
+}
+
+/// Triggers started by [`SpinSandbox::start_triggers`], stopped when dropped.
+struct RunningTriggers {
+    futures: Vec<BoxFuture<'static, Result<()>>>,
+    trigger_types: Vec<String>,
+    // Kept until the triggers stop, so that a reload only removes the files of the previous
+    // version once it replaced it
+    _files_dir: Option<TempDir>,
+}
+
+impl RunningTriggers {
+    async fn wait(self) -> Result<()> {
+        let trigger_type_map = self.trigger_types;
         // exit as soon as any of the trigger completes/exits
-        let (result, index, rest) = future::select_all(futures_list).await;
+        let (result, index, rest) = future::select_all(self.futures).await;
         let trigger_type = &trigger_type_map[index];
 
-        info!(" >>> trigger type '{trigger_type}' exited");
+        match &result {
+            Ok(()) => info!(" >>> trigger type '{trigger_type}' (index {index}) exited"),
+            Err(err) => log::error!(
+                " >>> trigger type '{trigger_type}' (index {index}) exited with error: {err:?}"
+            ),
+        }
+        info!(" >>> trigger index mapping: {trigger_type_map:?}");
 
         drop(rest);
 
@@ -224,46 +470,542 @@ impl SpinSandbox {
     }
 }
 
+impl SpinCompiler {
+    /// Creates a compiler whose engine is configured like the one Spin executes components with,
+    /// including the [`EngineOptions`] of the node config.
+    pub(crate) fn new() -> Result<Self> {
+        let node_config = node_config::get()?;
+        let options = node_config.engine.clone();
+        let mut config = options.spin_config();
+        Ok(Self(
+            wasmtime::Engine::new(config.wasmtime_config())?,
+            options,
+            node_config.preinit.clone(),
+            signature::trust_policy()?.cloned(),
+            node_config.trust_image_precompiled,
+        ))
+    }
+
+    /// Precompiles the components of `locked_app` whose sources are not precompiled yet into
+    /// `cache_dir` and points them at the precompiled artifacts. Artifacts are keyed by the
+    /// SHA-256 hashes of the component content and of [`Compiler::cache_key`], so they are reused
+    /// by every container of the node running the same components.
+    ///
+    /// Spin does not compose the dependencies of components it loads precompiled, so components
+    /// with dependencies are composed first, and the artifact of the composition replaces them.
+    /// Compositions of OCI layers are keyed by [`compose::composition_key`] instead, and are only
+    /// composed if not cached yet by [`Compiler::compile`].
+    pub(crate) async fn precompile_components(
+        &self,
+        locked_app: &mut LockedApp,
+        cache_dir: &Path,
+    ) -> Result<()> {
+        tokio::fs::create_dir_all(cache_dir)
+            .await
+            .with_context(|| format!("failed to create precompile cache at {cache_dir:?}"))?;
+        let engine_key = sha256_hash(&self.cache_key());
+
+        for component in &mut locked_app.components {
+            let source_path = component_source_path(component)?;
+            if wasmtime::Engine::detect_precompiled_file(&source_path)?.is_some() {
+                continue;
+            }
+            let precompiled_path = if component.dependencies.is_empty() {
+                let wasm = tokio::fs::read(&source_path)
+                    .await
+                    .with_context(|| format!("failed to read {source_path:?}"))?;
+                self.precompile_file(wasm, cache_dir, &engine_key).await
+            } else if let Some(file_name) = composition_file_name(component, &engine_key) {
+                let composition = async {
+                    compose::compose_component(component)
+                        .await
+                        .with_context(|| format!("failed to compose component {:?}", component.id))
+                };
+                self.precompile_cached(cache_dir, &file_name, composition)
+                    .await
+            } else {
+                let wasm = compose::compose_component(component)
+                    .await
+                    .with_context(|| format!("failed to compose component {:?}", component.id))?;
+                self.precompile_file(wasm, cache_dir, &engine_key).await
+            }
+            .with_context(|| format!("failed to precompile component {:?}", component.id))?;
+            let url = url::Url::from_file_path(&precompiled_path)
+                .map_err(|_| anyhow::anyhow!("invalid precompiled path {precompiled_path:?}"))?;
+            component.source.content.source = Some(url.to_string());
+            component.dependencies.clear();
+        }
+        Ok(())
+    }
+
+    /// Precompiles the components of a file-based application with
+    /// [`precompile_components`](Self::precompile_components). Returns whether it is safe to
+    /// enable loading AOT compiled components: the application is left untouched, to be compiled
+    /// by Spin at load time, if a component fails to precompile or if a component source provided
+    /// by the application is itself a precompiled artifact.
+    pub(crate) async fn precompile_file_components(
+        &self,
+        locked_app: &mut LockedApp,
+        cache_dir: &Path,
+    ) -> Result<bool> {
+        for component in &locked_app.components {
+            let source_path = component_source_path(component)?;
+            if matches!(
+                wasmtime::Engine::detect_precompiled_file(&source_path),
+                Ok(Some(_))
+            ) {
+                log::warn!(
+                    "component {:?} source {source_path:?} is a precompiled artifact; loading AOT compiled components is disabled",
+                    component.id
+                );
+                return Ok(false);
+            }
+        }
+        let mut precompiled_app = locked_app.clone();
+        self.precompile_components(&mut precompiled_app, cache_dir)
+            .await?;
+        *locked_app = precompiled_app;
+        Ok(true)
+    }
+
+    /// Returns the path of the precompiled artifact for `wasm`, precompiling it into `cache_dir`
+    /// unless it is already cached.
+    async fn precompile_file(
+        &self,
+        wasm: Vec<u8>,
+        cache_dir: &Path,
+        engine_key: &str,
+    ) -> Result<PathBuf> {
+        let content_hash = format!("{:x}", Sha256::digest(&wasm));
+        let file_name = format!("{content_hash}-{engine_key}.cwasm");
+        self.precompile_cached(cache_dir, &file_name, async { Ok(wasm) })
+            .await
+    }
+
+    /// Returns the path of the precompiled artifact `file_name` in `cache_dir`, precompiling the
+    /// output of `wasm` into it unless it is already cached; `wasm` is not awaited on a hit.
+    /// Cached artifacts are loaded as native code, so they are only used if recorded as
+    /// precompiled by the shim under their file name.
+    async fn precompile_cached(
+        &self,
+        cache_dir: &Path,
+        file_name: &str,
+        wasm: impl Future<Output = Result<Vec<u8>>>,
+    ) -> Result<PathBuf> {
+        let precompiled_path = cache_dir.join(file_name);
+        match tokio::fs::read(&precompiled_path).await {
+            Ok(cached) if precompiled::is_recorded(&cached, file_name) => {
+                log::info!("using cached precompiled artifact {precompiled_path:?}");
+                return Ok(precompiled_path);
+            }
+            Ok(_) => log::warn!(
+                "cached artifact {precompiled_path:?} was not precompiled by the shim; precompiling it again"
+            ),
+            Err(_) => {}
+        }
+
+        let wasm = wasm.await?;
+        log::info!("precompiling {precompiled_path:?}");
+        let engine = self.0.clone();
+        let preinit = self.2.clone();
+        let precompiled =
+            tokio::task::spawn_blocking(move || precompile_wasm(&engine, &wasm, preinit.as_ref()))
+                .await??;
+        precompiled::record(&precompiled, file_name);
+        // Write to a temporary file first so that a restarted container never observes a partial artifact
+        let temp_path = cache_dir.join(format!(".{file_name}.{}", std::process::id()));
+        tokio::fs::write(&temp_path, &precompiled)
+            .await
+            .with_context(|| format!("failed to write {temp_path:?}"))?;
+        tokio::fs::rename(&temp_path, &precompiled_path)
+            .await
+            .with_context(|| {
+                format!("failed to move precompiled artifact to {precompiled_path:?}")
+            })?;
+        Ok(precompiled_path)
+    }
+
+    /// Composes the components of `locked_app` with dependencies from the wasm `layers` of the
+    /// image, and precompiles the compositions into the precompile cache of the node, where
+    /// containers of the image find them by [`compose::composition_key`].
+    async fn precompile_compositions(
+        &self,
+        locked_app: &LockedApp,
+        layers: &[&WasmLayer],
+    ) -> Result<()> {
+        let cache_dir = precompile_cache_dir();
+        tokio::fs::create_dir_all(&cache_dir)
+            .await
+            .with_context(|| format!("failed to create precompile cache at {cache_dir:?}"))?;
+        let engine_key = sha256_hash(&self.cache_key());
+        for component in &locked_app.components {
+            let Some(file_name) = composition_file_name(component, &engine_key) else {
+                continue;
+            };
+            let composition = async {
+                compose::compose_component_from_layers(component, layers)
+                    .await
+                    .with_context(|| format!("failed to compose component {:?}", component.id))
+            };
+            self.precompile_cached(&cache_dir, &file_name, composition)
+                .await
+                .with_context(|| {
+                    format!("failed to precompile composed component {:?}", component.id)
+                })?;
+        }
+        Ok(())
+    }
+}
+
+// Returns the file name of the precompiled composition of `component` with its dependencies, or
+// None if it has no dependencies or its composition is not keyed by layer digests
+fn composition_file_name(component: &LockedComponent, engine_key: &str) -> Option<String> {
+    if component.dependencies.is_empty() {
+        return None;
+    }
+    compose::composition_key(component).map(|key| format!("composition-{key}-{engine_key}.cwasm"))
+}
+
+// Returns the local path of the source of `component`
+fn component_source_path(component: &LockedComponent) -> Result<PathBuf> {
+    let source = component
+        .source
+        .content
+        .source
+        .as_deref()
+        .with_context(|| format!("component {:?} has no source", component.id))?;
+    url::Url::parse(source)
+        .ok()
+        .and_then(|url| url.to_file_path().ok())
+        .with_context(|| {
+            format!(
+                "component {:?} has a non-file source {source:?}",
+                component.id
+            )
+        })
+}
+
 impl Compiler for SpinCompiler {
     fn cache_key(&self) -> impl Hash {
-        self.0.precompile_compatibility_hash()
+        (
+            self.0.precompile_compatibility_hash(),
+            &self.1,
+            &self.2,
+            &self.3,
+            self.4,
+        )
     }
 
     async fn compile(&self, layers: &[WasmLayer]) -> Result<Vec<Option<Vec<u8>>>> {
-        // Runwasi expects layers to be returned in the same order, so wrap each layer in an option, setting non Wasm layers to None
-        let precompiled_layers = layers
+        // Images are verified before their layers are compiled, and again by containers before
+        // their layers are used. The trust policy is part of the cache key, so images are
+        // compiled again when it changes.
+        if let Some(trust_policy) = &self.3 {
+            for layer in layers {
+                verify_descriptor(layer)?;
+            }
+            trust_policy.check(layers)?;
+        }
+        // Only wasm layers are compiled, so only compressed wasm layers are decompressed, and the
+        // content of other layers, such as large data layers, is left alone
+        let wasm_layers = layers
             .iter()
-            .map(|layer| match is_wasm_content(layer) {
-                Some(wasm_layer) => {
-                    log::info!(
-                        "Precompile called for wasm layer {:?}",
-                        wasm_layer.config.digest()
-                    );
-                    if wasmtime::Engine::detect_precompiled(&wasm_layer.layer).is_some() {
-                        log::info!("Layer already precompiled {:?}", wasm_layer.config.digest());
-                        Ok(Some(wasm_layer.layer))
-                    } else {
-                        let component =
-                            spin_componentize::componentize_if_necessary(&wasm_layer.layer)?;
-                        let precompiled = self.0.precompile_component(&component)?;
-                        Ok(Some(precompiled))
-                    }
-                }
-                None => Ok(None),
+            .map(|layer| Ok(decompress_wasm_layer(layer)?.or_else(|| is_wasm_content(layer))))
+            .collect::<Result<Vec<_>>>()?;
+        let locked_app = compose::locked_app_from_layers(layers)?;
+        // Component ids using each layer, to attribute precompile errors and logs
+        let component_ids: Arc<HashMap<String, Vec<String>>> = Arc::new(
+            locked_app
+                .as_ref()
+                .map(compose::component_ids_by_digest)
+                .unwrap_or_default(),
+        );
+        // Components with dependencies are composed and precompiled into the precompile cache of
+        // the node, as the same layers may be composed with different dependencies
+        if let Some(locked_app) = &locked_app {
+            let layers = wasm_layers.iter().flatten().collect::<Vec<_>>();
+            self.precompile_compositions(locked_app, &layers).await?;
+        }
+        let composed_digests = locked_app
+            .as_ref()
+            .map(compose::composed_layer_digests)
+            .unwrap_or_default();
+
+        let (unique_layers, output_indices) = unique_wasm_layers(&wasm_layers, &composed_digests);
+
+        // Original wasm of precompiled layers by digest, used to recompile those that are not
+        // compatible with this engine
+        let source_digests = unique_layers
+            .iter()
+            .filter_map(|layer| layer.config.annotations().as_ref())
+            .filter_map(|annotations| annotations.get(constants::OCI_ANNOTATION_PRECOMPILED_SOURCE))
+            .collect::<HashSet<_>>();
+        let sources: Arc<HashMap<String, Vec<u8>>> = Arc::new(
+            unique_layers
+                .iter()
+                .filter(|layer| source_digests.contains(&layer.config.digest().to_string()))
+                .filter(|layer| wasmtime::Engine::detect_precompiled(&layer.layer).is_none())
+                .map(|layer| (layer.config.digest().to_string(), layer.layer.clone()))
+                .collect(),
+        );
+
+        let parallelism = precompile_parallelism();
+        log::info!(
+            "Precompiling {} unique wasm layers with up to {parallelism} workers",
+            unique_layers.len()
+        );
+        let trust_image_precompiled = self.4;
+        let outputs: Vec<LayerOutput> = stream::iter(unique_layers.into_iter().map(|wasm_layer| {
+            let engine = self.0.clone();
+            let preinit = self.2.clone();
+            let sources = sources.clone();
+            let component_ids = component_ids.clone();
+            let digest = wasm_layer.config.digest().to_string();
+            tokio::task::spawn_blocking(move || -> Result<LayerOutput> {
+                let ids = component_ids.get(&digest).cloned().unwrap_or_default();
+                let started_at = Instant::now();
+                let output = precompile_layer(
+                    &engine,
+                    preinit.as_ref(),
+                    wasm_layer,
+                    &sources,
+                    trust_image_precompiled,
+                )
+                .with_context(|| {
+                    format!("failed to precompile layer {digest} used by components {ids:?}")
+                })?;
+                log::info!(
+                    "Precompiled layer {digest} used by components {ids:?} in {:?} ({} bytes)",
+                    started_at.elapsed(),
+                    output.artifact().len()
+                );
+                Ok(output)
             })
-            .collect::<anyhow::Result<_>>()?;
-        Ok(precompiled_layers)
+        }))
+        .buffered(parallelism)
+        .map(|joined| {
+            joined
+                .context("precompile worker failed")
+                .and_then(|result| result)
+        })
+        .try_collect()
+        .await?;
+
+        // Containers only load precompiled content recorded for the layer it substitutes, unless
+        // the node config trusts precompiled layers of images
+        for (layer, index) in layers.iter().zip(&output_indices) {
+            if let Some(LayerOutput::Precompiled(artifact)) = index.map(|index| &outputs[index]) {
+                precompiled::record(artifact, &layer.config.digest().to_string());
+            }
+        }
+        Ok(output_indices
+            .into_iter()
+            .map(|index| index.map(|index| outputs[index].artifact().to_vec()))
+            .collect())
+    }
+}
+
+// Returns the wasm layers to compile, and for each layer of the image, given by its wasm content in
+// `wasm_layers` if any, the index of its compiled output among them. Runwasi expects layers to be
+// returned in the same order, so non Wasm layers are left as None. Layers with identical content
+// are compiled once; the content is hashed, as descriptor digests are not checked against it here.
+fn unique_wasm_layers(
+    wasm_layers: &[Option<WasmLayer>],
+    composed_digests: &HashSet<String>,
+) -> (Vec<WasmLayer>, Vec<Option<usize>>) {
+    let mut unique_layers: Vec<WasmLayer> = Vec::new();
+    let mut content_indices: HashMap<String, usize> = HashMap::new();
+    let output_indices = wasm_layers
+        .iter()
+        .map(|wasm_layer| {
+            let wasm_layer = wasm_layer.clone()?;
+            // WIT packages of wasm packages are not run, so are left uncompiled
+            if is_wit_package(&wasm_layer.layer).unwrap_or_default() {
+                return None;
+            }
+            if composed_digests.contains(&wasm_layer.config.digest().to_string()) {
+                return None;
+            }
+            let content_hash = format!("{:x}", Sha256::digest(&wasm_layer.layer));
+            let index = *content_indices.entry(content_hash).or_insert_with(|| {
+                unique_layers.push(wasm_layer);
+                unique_layers.len() - 1
+            });
+            Some(index)
+        })
+        .collect();
+    (unique_layers, output_indices)
+}
+
+/// Output of [`precompile_layer`] for a wasm layer.
+enum LayerOutput {
+    /// Artifact precompiled by the shim, recorded as such for containers to load it
+    Precompiled(Vec<u8>),
+    /// Precompiled layer of the image, passed through as trusted by the node config
+    PassedThrough(Vec<u8>),
+}
+
+impl LayerOutput {
+    fn artifact(&self) -> &[u8] {
+        match self {
+            Self::Precompiled(artifact) | Self::PassedThrough(artifact) => artifact,
+        }
     }
 }
 
+/// Precompiles a single wasm layer.
+///
+/// Precompiled layers of the image are never deserialized by the shim, which would run native
+/// code of the image outside the container, and are only loaded by containers if
+/// `trust_image_precompiled`. Otherwise, they are recompiled from the original wasm named by their
+/// [`constants::OCI_ANNOTATION_PRECOMPILED_SOURCE`] annotation, or rejected if the image does not
+/// contain that layer.
+///
+/// Trusted layers whose [`constants::OCI_ANNOTATION_PRECOMPILED_ENGINE`] annotation matches
+/// [`precompiled_engine_annotation`] are passed through. Trusted layers that are unannotated or
+/// annotated for another engine are recompiled from their original wasm if the image contains it.
+/// Otherwise, layers annotated for another engine are rejected, and unannotated layers are passed
+/// through for wasmtime to check when they are loaded.
+fn precompile_layer(
+    engine: &wasmtime::Engine,
+    preinit: Option<&Preinitializer>,
+    wasm_layer: WasmLayer,
+    sources: &HashMap<String, Vec<u8>>,
+    trust_image_precompiled: bool,
+) -> Result<LayerOutput> {
+    let digest = wasm_layer.config.digest();
+    log::info!("Precompile called for wasm layer {digest:?}");
+    if wasmtime::Engine::detect_precompiled(&wasm_layer.layer).is_none() {
+        return precompile_wasm(engine, &wasm_layer.layer, preinit).map(LayerOutput::Precompiled);
+    }
+    let annotations = wasm_layer.config.annotations().as_ref();
+    let engine_annotation = annotations
+        .and_then(|annotations| annotations.get(constants::OCI_ANNOTATION_PRECOMPILED_ENGINE));
+    let expected_engine = precompiled_engine_annotation();
+    if trust_image_precompiled && engine_annotation == Some(&expected_engine) {
+        log::info!("Layer already precompiled {digest:?}");
+        return Ok(LayerOutput::PassedThrough(wasm_layer.layer));
+    }
+    let source = annotations
+        .and_then(|annotations| annotations.get(constants::OCI_ANNOTATION_PRECOMPILED_SOURCE))
+        .and_then(|source_digest| sources.get(source_digest).map(|wasm| (source_digest, wasm)));
+    match (engine_annotation, source) {
+        (_, Some((source_digest, wasm))) => {
+            log::warn!(
+                "Layer {digest:?} was precompiled by the image for engine {engine_annotation:?}, expected {expected_engine:?}; recompiling from source layer {source_digest:?}"
+            );
+            precompile_wasm(engine, wasm, preinit).map(LayerOutput::Precompiled)
+        }
+        _ if !trust_image_precompiled => bail!(
+            "layer {digest} is precompiled by the image, which is only loaded if the node config sets trust_image_precompiled, and the image does not contain its original wasm"
+        ),
+        (None, None) => {
+            log::info!(
+                "Layer already precompiled {digest:?}, without an engine annotation or source layer; compatibility is checked when it is loaded"
+            );
+            Ok(LayerOutput::PassedThrough(wasm_layer.layer))
+        }
+        (Some(engine_annotation), None) => bail!(
+            "layer {digest} is precompiled for an incompatible engine ({engine_annotation}, expected {expected_engine}) and the image does not contain its original wasm"
+        ),
+    }
+}
+
+/// Returns the value of the [`constants::OCI_ANNOTATION_PRECOMPILED_ENGINE`] annotation of layers
+/// precompiled for the shim: the wasmtime version and target, `wasmtime-<version>/<arch>-<os>`.
+///
+/// These are the published inputs of wasmtime's compatibility check that image builders can
+/// reproduce. Wasmtime still checks the engine settings when containers load the layer.
+pub(crate) fn precompiled_engine_annotation() -> String {
+    format!(
+        "wasmtime-{}/{}-{}",
+        constants::WASMTIME_VERSION,
+        std::env::consts::ARCH,
+        std::env::consts::OS
+    )
+}
+
+/// Pre-initializes the given wasm if enabled, componentizes it if necessary and precompiles it.
+fn precompile_wasm(
+    engine: &wasmtime::Engine,
+    wasm: &[u8],
+    preinit: Option<&Preinitializer>,
+) -> Result<Vec<u8>> {
+    let preinitialized = match preinit {
+        Some(preinit) => preinit.preinitialize(wasm)?,
+        None => None,
+    };
+    let wasm = preinitialized.as_deref().unwrap_or(wasm);
+    let component = spin_componentize::componentize_if_necessary(wasm)?;
+    Ok(engine.precompile_component(&component)?)
+}
+
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr as _;
+    use std::{str::FromStr as _, sync::Mutex};
 
     use oci_spec::image::{Digest, MediaType};
 
     use super::*;
 
+    // Reports a change on each of its first `self.0` waits
+    struct TestWatcher(usize);
+
+    impl Watch for TestWatcher {
+        async fn changed(&mut self) {
+            if self.0 == 0 {
+                future::pending::<()>().await;
+            }
+            self.0 -= 1;
+        }
+    }
+
+    // Records an event when dropped
+    struct OnDrop(Arc<Mutex<Vec<&'static str>>>, &'static str);
+
+    impl Drop for OnDrop {
+        fn drop(&mut self) {
+            self.0.lock().unwrap().push(self.1);
+        }
+    }
+
+    #[tokio::test]
+    async fn reloaded_version_replaces_running_one_once_built() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let running: BoxFuture<'static, Result<()>> = {
+            let stopped = OnDrop(events.clone(), "v1 stopped");
+            Box::pin(async move {
+                let _stopped = stopped;
+                future::pending::<Result<()>>().await
+            })
+        };
+        let mut attempts = 0;
+        let result = run_reloading(running, TestWatcher(2), || {
+            attempts += 1;
+            let events = events.clone();
+            let attempt = attempts;
+            async move {
+                if attempt == 1 {
+                    events.lock().unwrap().push("v2 failed to build");
+                    bail!("build failed");
+                }
+                events.lock().unwrap().push("v2 built");
+                let running: BoxFuture<'static, Result<()>> = Box::pin(async move {
+                    events.lock().unwrap().push("v2 started");
+                    bail!("v2 exited")
+                });
+                Ok((running, None))
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap_err().to_string(), "v2 exited");
+        assert_eq!(
+            *events.lock().unwrap(),
+            ["v2 failed to build", "v2 built", "v1 stopped", "v2 started"]
+        );
+    }
+
     #[tokio::test]
     async fn precompile() {
         let module = wat::parse_str("(module)").unwrap();
@@ -285,7 +1027,7 @@ mod tests {
                     .unwrap(),
                 ),
             },
-            // Precompiled
+            // Precompiled, passed through as precompiled layers are trusted
             WasmLayer {
                 layer: component.to_owned(),
                 config: oci_spec::image::Descriptor::new(
@@ -310,7 +1052,7 @@ mod tests {
                 ),
             },
         ];
-        let compiler = SpinCompiler(wasmtime_engine);
+        let compiler = SpinCompiler(wasmtime_engine, EngineOptions::default(), None, None, true);
         let precompiled = compiler
             .compile(&wasm_layers)
             .await
@@ -323,4 +1065,422 @@ mod tests {
         );
         assert!(precompiled[2].is_none());
     }
+
+    #[tokio::test]
+    async fn compressed_data_layers_are_not_decompressed() {
+        // Not valid zstd, so compile would fail if it decompressed the layer
+        let data_layer = WasmLayer {
+            layer: b"not zstd".to_vec(),
+            config: oci_spec::image::Descriptor::new(
+                MediaType::Other(constants::OCI_LAYER_MEDIA_TYPE_DATA_ZSTD.to_string()),
+                8,
+                Digest::from_str(
+                    "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b",
+                )
+                .unwrap(),
+            ),
+        };
+        let compiler = SpinCompiler(
+            wasmtime::Engine::default(),
+            EngineOptions::default(),
+            None,
+            None,
+            false,
+        );
+        let precompiled = compiler
+            .compile(&[data_layer])
+            .await
+            .expect("compile failed");
+        assert!(precompiled[0].is_none());
+    }
+
+    #[tokio::test]
+    async fn precompile_identical_content_once() {
+        let module = wat::parse_str("(module)").unwrap();
+        let layer = WasmLayer {
+            layer: module.clone(),
+            config: oci_spec::image::Descriptor::new(
+                MediaType::Other(constants::OCI_LAYER_MEDIA_TYPE_WASM.to_string()),
+                1024,
+                Digest::from_str(
+                    "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b",
+                )
+                .unwrap(),
+            ),
+        };
+        let wasm_layers = vec![layer.clone(), layer.clone(), layer];
+
+        // Identical layers are handed to a single precompile worker
+        let (unique_layers, output_indices) = unique_wasm_layers(
+            &wasm_layers.iter().cloned().map(Some).collect::<Vec<_>>(),
+            &HashSet::new(),
+        );
+        assert_eq!(unique_layers.len(), 1);
+        assert_eq!(output_indices, [Some(0), Some(0), Some(0)]);
+
+        let compiler = SpinCompiler(
+            wasmtime::Engine::default(),
+            EngineOptions::default(),
+            None,
+            None,
+            false,
+        );
+        let precompiled = compiler
+            .compile(&wasm_layers)
+            .await
+            .expect("compile failed");
+        assert_eq!(precompiled.len(), 3);
+        let first = precompiled[0].as_deref().expect("no first entry");
+        assert_ne!(first, module);
+        assert!(precompiled
+            .iter()
+            .all(|entry| entry.as_deref() == Some(first)));
+    }
+
+    #[tokio::test]
+    async fn composed_layers_are_precompiled_into_the_node_cache() {
+        let wasm_layer = |wasm: Vec<u8>| {
+            let digest = format!("sha256:{:x}", Sha256::digest(&wasm));
+            WasmLayer {
+                layer: wasm,
+                config: oci_spec::image::Descriptor::new(
+                    MediaType::Other(constants::OCI_LAYER_MEDIA_TYPE_WASM.to_string()),
+                    1024,
+                    Digest::from_str(&digest).unwrap(),
+                ),
+            }
+        };
+        let main = wasm_layer(
+            wat::parse_str(r#"(component (import "test:dep/iface" (instance)))"#).unwrap(),
+        );
+        let dependency = wasm_layer(
+            wat::parse_str(r#"(component (instance $i) (export "test:dep/iface" (instance $i)))"#)
+                .unwrap(),
+        );
+        let standalone = wasm_layer(wat::parse_str("(module)").unwrap());
+        let app_json = format!(
+            r#"{{
+                "spin_lock_version": 1,
+                "components": [
+                    {{
+                        "id": "composed",
+                        "source": {{ "content_type": "application/wasm", "content": {{ "digest": "{}" }} }},
+                        "dependencies": {{
+                            "test:dep/iface": {{
+                                "source": {{ "content_type": "application/wasm", "content": {{ "digest": "{}" }} }}
+                            }}
+                        }}
+                    }},
+                    {{ "id": "standalone", "source": {{ "content_type": "application/wasm", "content": {{ "digest": "{}" }} }} }}
+                ],
+                "triggers": []
+            }}"#,
+            main.config.digest(),
+            dependency.config.digest(),
+            standalone.config.digest()
+        );
+        let app_layer = WasmLayer {
+            layer: app_json.clone().into_bytes(),
+            config: oci_spec::image::Descriptor::new(
+                MediaType::Other(spin_oci::client::SPIN_APPLICATION_MEDIA_TYPE.to_string()),
+                1024,
+                Digest::from_str(
+                    "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b",
+                )
+                .unwrap(),
+            ),
+        };
+        let compiler = SpinCompiler(
+            wasmtime::Engine::default(),
+            EngineOptions::default(),
+            None,
+            None,
+            false,
+        );
+        let precompiled = compiler
+            .compile(&[app_layer, main.clone(), dependency.clone(), standalone])
+            .await
+            .expect("compile failed");
+        assert!(precompiled[0].is_none());
+        // Composition outputs would be cached by the main layer digest, so they are cached by
+        // composition key in the node cache instead
+        assert!(precompiled[1].is_none());
+        assert!(precompiled[2].is_none());
+        assert!(precompiled[3].is_some());
+
+        let mut locked_app: LockedApp = serde_json::from_str(&app_json).unwrap();
+        let engine_key = sha256_hash(&compiler.cache_key());
+        let file_name = composition_file_name(&locked_app.components[0], &engine_key).unwrap();
+        let cached = std::fs::read(precompile_cache_dir().join(&file_name)).unwrap();
+        assert!(precompiled::is_recorded(&cached, &file_name));
+
+        // Containers find the composition by key instead of composing their local sources
+        let dir = tempfile::tempdir().unwrap();
+        let source_url = |name: &str, layer: &WasmLayer| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, &layer.layer).unwrap();
+            url::Url::from_file_path(path).unwrap().to_string()
+        };
+        let component = &mut locked_app.components[0];
+        component.source.content.source = Some(source_url("main.wasm", &main));
+        for dependency_source in component.dependencies.values_mut() {
+            dependency_source.source.content.source = Some(source_url("dep.wasm", &dependency));
+        }
+        locked_app.components.truncate(1);
+        compiler
+            .precompile_components(&mut locked_app, &precompile_cache_dir())
+            .await
+            .expect("precompile failed");
+        let source = component_source_path(&locked_app.components[0]).unwrap();
+        assert_eq!(source, precompile_cache_dir().join(&file_name));
+        assert!(locked_app.components[0].dependencies.is_empty());
+    }
+
+    #[tokio::test]
+    async fn winch_compiled_component_instantiates() {
+        let options = EngineOptions {
+            strategy: Some(crate::engine_options::Strategy::Winch),
+            pooling_allocator: Some(false),
+            ..Default::default()
+        };
+        let mut config = options.spin_config();
+        let engine = wasmtime::Engine::new(config.wasmtime_config()).unwrap();
+        let compiler = SpinCompiler(engine.clone(), options, None, None, false);
+        let wasm = wat::parse_str(
+            r#"(component
+                (core module $m (func (export "answer") (result i32) i32.const 42))
+                (core instance $i (instantiate $m))
+                (func (export "answer") (result u32) (canon lift (core func $i "answer")))
+            )"#,
+        )
+        .unwrap();
+        let digest = format!("sha256:{:x}", Sha256::digest(&wasm));
+        let layer = WasmLayer {
+            layer: wasm,
+            config: oci_spec::image::Descriptor::new(
+                MediaType::Other(constants::OCI_LAYER_MEDIA_TYPE_WASM.to_string()),
+                1024,
+                Digest::from_str(&digest).unwrap(),
+            ),
+        };
+        let precompiled = compiler
+            .compile(&[layer])
+            .await
+            .expect("compile failed")
+            .remove(0)
+            .expect("layer was not precompiled");
+
+        // SAFETY: the artifact was just precompiled by this engine
+        let component =
+            unsafe { wasmtime::component::Component::deserialize(&engine, &precompiled) }.unwrap();
+        let linker = wasmtime::component::Linker::<()>::new(&engine);
+        let mut store = wasmtime::Store::new(&engine, ());
+        store.set_epoch_deadline(u64::MAX / 2);
+        let instance = linker
+            .instantiate_async(&mut store, &component)
+            .await
+            .unwrap();
+        let answer = instance
+            .get_typed_func::<(), (u32,)>(&mut store, "answer")
+            .unwrap();
+        assert_eq!(answer.call_async(&mut store, ()).await.unwrap(), (42,));
+    }
+
+    #[tokio::test]
+    async fn precompiled_layer_for_incompatible_engine() {
+        let mut config = wasmtime::Config::new();
+        config.epoch_interruption(true);
+        let other_engine = wasmtime::Engine::new(&config).unwrap();
+        let component = wasmtime::component::Component::new(&other_engine, "(component)")
+            .unwrap()
+            .serialize()
+            .unwrap();
+        let source = wat::parse_str("(component)").unwrap();
+        let source_digest = format!("sha256:{:x}", Sha256::digest(&source));
+        let precompiled_digest = format!("sha256:{:x}", Sha256::digest(&component));
+        // Layer precompiled for the engine it is annotated with, if any
+        let precompiled_layer = |engine: Option<&str>, with_source: bool| {
+            let mut config = oci_spec::image::Descriptor::new(
+                MediaType::Other(constants::OCI_LAYER_MEDIA_TYPE_WASM.to_string()),
+                1024,
+                Digest::from_str(&precompiled_digest).unwrap(),
+            );
+            let mut annotations = HashMap::new();
+            if let Some(engine) = engine {
+                annotations.insert(
+                    constants::OCI_ANNOTATION_PRECOMPILED_ENGINE.to_string(),
+                    engine.to_string(),
+                );
+            }
+            if with_source {
+                annotations.insert(
+                    constants::OCI_ANNOTATION_PRECOMPILED_SOURCE.to_string(),
+                    source_digest.clone(),
+                );
+            }
+            config.set_annotations(Some(annotations));
+            WasmLayer {
+                layer: component.clone(),
+                config,
+            }
+        };
+        let source_layer = WasmLayer {
+            config: oci_spec::image::Descriptor::new(
+                MediaType::Other(constants::OCI_LAYER_MEDIA_TYPE_WASM.to_string()),
+                source.len() as u64,
+                Digest::from_str(&source_digest).unwrap(),
+            ),
+            layer: source,
+        };
+        let compiler = SpinCompiler(
+            wasmtime::Engine::default(),
+            EngineOptions::default(),
+            None,
+            None,
+            false,
+        );
+
+        let trusting_compiler = SpinCompiler(
+            wasmtime::Engine::default(),
+            EngineOptions::default(),
+            None,
+            None,
+            true,
+        );
+
+        let compatible = precompiled_engine_annotation();
+        let incompatible = "wasmtime-1.0.0/x86_64-linux";
+
+        // Layers annotated with a compatible engine are only passed through if trusted, and are
+        // not recorded as precompiled by the shim
+        let err = compiler
+            .compile(&[precompiled_layer(Some(&compatible), false)])
+            .await
+            .expect_err("untrusted layer should be rejected");
+        assert!(
+            format!("{err:#}").contains("trust_image_precompiled"),
+            "error should name the node config setting: {err:#}"
+        );
+        let precompiled = trusting_compiler
+            .compile(&[precompiled_layer(Some(&compatible), false)])
+            .await
+            .expect("compile failed");
+        assert_eq!(precompiled[0].as_deref(), Some(component.as_slice()));
+        assert!(!precompiled::is_recorded(&component, &precompiled_digest));
+
+        // Without the original wasm the layer is rejected
+        let err = trusting_compiler
+            .compile(&[precompiled_layer(Some(incompatible), false)])
+            .await
+            .expect_err("incompatible layer should be rejected");
+        assert!(
+            format!("{err:#}").contains(&precompiled_digest),
+            "error should name the layer digest: {err:#}"
+        );
+
+        // With the original wasm, untrusted, incompatible and unannotated layers are recompiled
+        for (compiler, engine) in [
+            (&compiler, Some(compatible.as_str())),
+            (&trusting_compiler, Some(incompatible)),
+            (&trusting_compiler, None),
+        ] {
+            let precompiled = compiler
+                .compile(&[precompiled_layer(engine, true), source_layer.clone()])
+                .await
+                .expect("compile failed");
+            let recompiled = precompiled[0].as_deref().expect("no first entry");
+            assert_ne!(recompiled, component);
+            assert!(precompiled::is_recorded(recompiled, &precompiled_digest));
+            // SAFETY: the artifact was just produced by the compiler under test
+            let deserialized =
+                unsafe { wasmtime::component::Component::deserialize(&compiler.0, recompiled) };
+            assert!(deserialized.is_ok());
+        }
+    }
+
+    #[test]
+    fn wasmtime_version_matches_dependency() {
+        let manifest: toml::Table = toml::from_str(include_str!("../Cargo.toml")).unwrap();
+        assert_eq!(
+            manifest["dependencies"]["wasmtime"]["version"].as_str(),
+            Some(constants::WASMTIME_VERSION)
+        );
+    }
+
+    #[tokio::test]
+    async fn precompile_file_components_uses_cache() {
+        let app_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let wasm_path = app_dir.path().join("component.wasm");
+        std::fs::write(&wasm_path, wat::parse_str("(module)").unwrap()).unwrap();
+        let source = url::Url::from_file_path(&wasm_path).unwrap();
+        let app_json = format!(
+            r#"{{
+                "spin_lock_version": 1,
+                "components": [{{
+                    "id": "hello",
+                    "source": {{ "content_type": "application/wasm", "content": {{ "source": "{source}" }} }}
+                }}],
+                "triggers": []
+            }}"#
+        );
+        let compiler = SpinCompiler::new().unwrap();
+
+        let mut locked_app = LockedApp::from_json(app_json.as_bytes()).unwrap();
+        let aot_safe = compiler
+            .precompile_file_components(&mut locked_app, cache_dir.path())
+            .await
+            .expect("precompile failed");
+        assert!(aot_safe);
+        let precompiled_source = locked_app.components[0]
+            .source
+            .content
+            .source
+            .clone()
+            .unwrap();
+        let precompiled_path = url::Url::parse(&precompiled_source)
+            .unwrap()
+            .to_file_path()
+            .unwrap();
+        assert!(precompiled_path.starts_with(cache_dir.path()));
+        assert_eq!(
+            wasmtime::Engine::detect_precompiled_file(&precompiled_path).unwrap(),
+            Some(wasmtime::Precompiled::Component)
+        );
+
+        // A second start reuses the cached artifact
+        let modified = std::fs::metadata(&precompiled_path)
+            .unwrap()
+            .modified()
+            .unwrap();
+        let mut locked_app = LockedApp::from_json(app_json.as_bytes()).unwrap();
+        compiler
+            .precompile_file_components(&mut locked_app, cache_dir.path())
+            .await
+            .expect("precompile failed");
+        assert_eq!(
+            locked_app.components[0].source.content.source,
+            Some(precompiled_source)
+        );
+        assert_eq!(
+            std::fs::metadata(&precompiled_path)
+                .unwrap()
+                .modified()
+                .unwrap(),
+            modified
+        );
+
+        // A planted artifact is not loaded, but precompiled again
+        let planted = wasmtime::component::Component::new(&compiler.0, "(component)")
+            .unwrap()
+            .serialize()
+            .unwrap();
+        std::fs::write(&precompiled_path, &planted).unwrap();
+        let mut locked_app = LockedApp::from_json(app_json.as_bytes()).unwrap();
+        compiler
+            .precompile_file_components(&mut locked_app, cache_dir.path())
+            .await
+            .expect("precompile failed");
+        assert_ne!(std::fs::read(&precompiled_path).unwrap(), planted);
+    }
 }
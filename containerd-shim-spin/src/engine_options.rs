@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+/// Cranelift optimization level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OptLevel {
+    None,
+    Speed,
+    SpeedAndSize,
+}
+
+/// Compilation strategy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Strategy {
+    /// Optimizing compiler, for peak throughput
+    Cranelift,
+    /// Baseline compiler, for fast compilation and cold starts
+    Winch,
+}
+
+/// Wasm proposals that can be enabled or disabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum WasmFeature {
+    Simd,
+    RelaxedSimd,
+    Threads,
+    MultiMemory,
+    Memory64,
+    TailCall,
+    FunctionReferences,
+    Gc,
+    WideArithmetic,
+    CustomPageSizes,
+}
+
+/// Node-level wasmtime engine settings, the `engine` section of the
+/// [`NodeConfig`](crate::node_config::NodeConfig), e.g.:
+///
+/// ```json
+/// { "strategy": "winch", "opt_level": "speed", "pooling_allocator": false, "wasm_features": { "tail-call": true, "threads": false } }
+/// ```
+///
+/// The same options are applied to the engine that precompiles components and the engine
+/// that executes them, and are part of the compiler cache key, so that a precompiled artifact
+/// is never loaded under a different configuration. Unset options keep the Spin defaults.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct EngineOptions {
+    pub(crate) strategy: Option<Strategy>,
+    pub(crate) opt_level: Option<OptLevel>,
+    pub(crate) pooling_allocator: Option<bool>,
+    pub(crate) epoch_interruption: Option<bool>,
+    /// Wasm proposals to enable (`true`) or disable (`false`)
+    pub(crate) wasm_features: BTreeMap<WasmFeature, bool>,
+}
+
+impl EngineOptions {
+    /// Applies the options on top of the given Spin engine configuration.
+    pub(crate) fn apply(&self, config: &mut spin_core::Config) {
+        if self.pooling_allocator == Some(false) {
+            config.disable_pooling();
+        }
+        let wasmtime_config = config.wasmtime_config();
+        if let Some(strategy) = self.strategy {
+            wasmtime_config.strategy(match strategy {
+                Strategy::Cranelift => wasmtime::Strategy::Cranelift,
+                Strategy::Winch => wasmtime::Strategy::Winch,
+            });
+        }
+        if let Some(opt_level) = self.opt_level {
+            wasmtime_config.cranelift_opt_level(match opt_level {
+                OptLevel::None => wasmtime::OptLevel::None,
+                OptLevel::Speed => wasmtime::OptLevel::Speed,
+                OptLevel::SpeedAndSize => wasmtime::OptLevel::SpeedAndSize,
+            });
+        }
+        if let Some(epoch_interruption) = self.epoch_interruption {
+            wasmtime_config.epoch_interruption(epoch_interruption);
+        }
+        for (feature, enable) in &self.wasm_features {
+            let enable = *enable;
+            match feature {
+                WasmFeature::Simd => wasmtime_config.wasm_simd(enable),
+                WasmFeature::RelaxedSimd => wasmtime_config.wasm_relaxed_simd(enable),
+                WasmFeature::Threads => wasmtime_config.wasm_threads(enable),
+                WasmFeature::MultiMemory => wasmtime_config.wasm_multi_memory(enable),
+                WasmFeature::Memory64 => wasmtime_config.wasm_memory64(enable),
+                WasmFeature::TailCall => wasmtime_config.wasm_tail_call(enable),
+                WasmFeature::FunctionReferences => wasmtime_config.wasm_function_references(enable),
+                WasmFeature::Gc => wasmtime_config.wasm_gc(enable),
+                WasmFeature::WideArithmetic => wasmtime_config.wasm_wide_arithmetic(enable),
+                WasmFeature::CustomPageSizes => wasmtime_config.wasm_custom_page_sizes(enable),
+            };
+        }
+    }
+
+    /// Returns the Spin engine configuration with the options applied.
+    pub(crate) fn spin_config(&self) -> spin_core::Config {
+        let mut config = spin_core::Config::default();
+        self.apply(&mut config);
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn engine_options_from_json() {
+        let options: EngineOptions = serde_json::from_str(
+            r#"{
+                "strategy": "winch",
+                "opt_level": "none",
+                "pooling_allocator": false,
+                "wasm_features": { "tail-call": true, "threads": false, "gc": true }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            options,
+            EngineOptions {
+                strategy: Some(Strategy::Winch),
+                opt_level: Some(OptLevel::None),
+                pooling_allocator: Some(false),
+                epoch_interruption: None,
+                wasm_features: BTreeMap::from([
+                    (WasmFeature::TailCall, true),
+                    (WasmFeature::Threads, false),
+                    (WasmFeature::Gc, true),
+                ]),
+            }
+        );
+    }
+
+    #[test]
+    fn engine_options_reject_invalid_values() {
+        assert!(serde_json::from_str::<EngineOptions>(r#"{ "opt_level": "fast" }"#).is_err());
+        assert!(serde_json::from_str::<EngineOptions>(r#"{ "strategy": "llvm" }"#).is_err());
+        assert!(serde_json::from_str::<EngineOptions>(
+            r#"{ "wasm_features": { "unknown": true } }"#
+        )
+        .is_err());
+        assert!(serde_json::from_str::<EngineOptions>(r#"{ "optlevel": "speed" }"#).is_err());
+    }
+}
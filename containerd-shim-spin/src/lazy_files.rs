@@ -0,0 +1,408 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::{ensure, Context, Result};
+use containerd_shim_wasm::sandbox::context::WasmLayer;
+use spin_app::{
+    locked::{ContentPath, ContentRef, LockedComponent},
+    AppComponent,
+};
+use spin_factor_wasi::{FilesMounter, MountFilesContext};
+use tokio::sync::OnceCell;
+
+use crate::{constants, content_cache, utils::write_data_content};
+
+/// Returns whether data layers are written when first mounted, see
+/// [`constants::SPIN_LAZY_DATA_LAYERS_ENV`].
+pub(crate) fn lazy_data_layers() -> bool {
+    env::var(constants::SPIN_LAZY_DATA_LAYERS_ENV).map_or(true, |lazy| lazy != "false")
+}
+
+/// A data or archive layer of an OCI application, held until written to the cache.
+struct DataLayer {
+    digest: String,
+    archive: bool,
+    // Released once written, so that only layers no component has mounted yet are held
+    layer: Mutex<Option<WasmLayer>>,
+}
+
+/// Data and archive layers of an OCI application, indexed at startup rather than written to the
+/// cache.
+#[derive(Clone, Default)]
+pub(crate) struct DataLayers(Vec<Arc<DataLayer>>);
+
+impl DataLayers {
+    /// Indexes a data layer. The content of compressed layers is decompressed when written.
+    pub(crate) fn add_data(&mut self, digest: impl Into<String>, layer: WasmLayer) {
+        self.add(digest.into(), false, layer)
+    }
+
+    /// Indexes an archive layer, unpacked when written.
+    pub(crate) fn add_archive(&mut self, digest: impl Into<String>, layer: WasmLayer) {
+        self.add(digest.into(), true, layer)
+    }
+
+    fn add(&mut self, digest: String, archive: bool, layer: WasmLayer) {
+        log::debug!(
+            "<<< indexed {} layer {digest} with length {}",
+            if archive { "archive" } else { "data" },
+            layer.layer.len()
+        );
+        self.0.push(Arc::new(DataLayer {
+            digest,
+            archive,
+            layer: Mutex::new(Some(layer)),
+        }));
+    }
+
+    /// Writes every layer to the cache.
+    pub(crate) fn write_all(&self, data_dir: &Path) -> Result<()> {
+        for layer in &self.0 {
+            layer.write(data_dir)?;
+        }
+        Ok(())
+    }
+
+    // Writes the layers holding the data files `digests` to the cache. Files of archives are only
+    // known once unpacked, so all archives are unpacked if a file is in none of the data layers.
+    fn write(&self, data_dir: &Path, digests: &[&str]) -> Result<()> {
+        let mut missing = false;
+        for digest in digests {
+            if content_cache::is_intact(&data_dir.join(digest), digest) {
+                continue;
+            }
+            match self.0.iter().find(|layer| layer.digest == *digest) {
+                Some(layer) => layer.write(data_dir)?,
+                None => missing = true,
+            }
+        }
+        if missing {
+            for layer in self.0.iter().filter(|layer| layer.archive) {
+                layer.write(data_dir)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DataLayer {
+    // Writes the layer to the cache, unless already written. Components sharing the layer wait
+    // for the first write.
+    fn write(&self, data_dir: &Path) -> Result<()> {
+        let mut layer = self.layer.lock().unwrap();
+        let Some(content) = layer.as_ref() else {
+            return Ok(());
+        };
+        if self.archive {
+            content_cache::unpack_archive(data_dir, content.layer.as_slice(), &self.digest)?;
+        } else {
+            content_cache::write_data(data_dir, &self.digest, |mut writer| {
+                write_data_content(content, &mut writer)
+            })?;
+        }
+        *layer = None;
+        Ok(())
+    }
+}
+
+/// Files of the components of an OCI application, copied from the cache into a mount directory
+/// per component when the component is first mounted.
+pub(crate) struct LazyFiles {
+    data_dir: PathBuf,
+    layers: DataLayers,
+    components: HashMap<String, ComponentFiles>,
+}
+
+struct ComponentFiles {
+    files: Vec<ContentPath>,
+    mount_dir: PathBuf,
+    mounted: OnceCell<()>,
+}
+
+impl LazyFiles {
+    pub(crate) fn new(data_dir: PathBuf, layers: DataLayers) -> Self {
+        Self {
+            data_dir,
+            layers,
+            components: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.components.is_empty()
+    }
+
+    /// Defers the files of `component` to its first mount, replacing them by a mount of
+    /// `mount_dir` at the root of the component.
+    pub(crate) fn defer_component_files(
+        &mut self,
+        component: &mut LockedComponent,
+        mount_dir: PathBuf,
+    ) -> Result<()> {
+        if component.files.is_empty() {
+            return Ok(());
+        }
+        let source = url::Url::from_directory_path(&mount_dir)
+            .map_err(|_| anyhow::anyhow!("invalid mount dir {mount_dir:?}"))?;
+        let files = std::mem::replace(
+            &mut component.files,
+            vec![ContentPath {
+                content: ContentRef {
+                    source: Some(source.to_string()),
+                    ..Default::default()
+                },
+                path: "/".into(),
+            }],
+        );
+        self.components.insert(
+            component.id.clone(),
+            ComponentFiles {
+                files,
+                mount_dir,
+                mounted: OnceCell::new(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Writes the files of component `id` to its mount directory, unless already mounted. Files
+    /// are copied on the blocking thread pool, and components are mounted concurrently.
+    pub(crate) async fn materialize(self: &Arc<Self>, id: &str) -> Result<()> {
+        let Some(component) = self.components.get(id) else {
+            return Ok(());
+        };
+        component
+            .mounted
+            .get_or_try_init(|| {
+                let lazy_files = self.clone();
+                let id = id.to_string();
+                async move {
+                    tokio::task::spawn_blocking(move || lazy_files.mount(&id))
+                        .await
+                        .context("file mount task failed")?
+                }
+            })
+            .await?;
+        Ok(())
+    }
+
+    fn mount(&self, id: &str) -> Result<()> {
+        let Some(component) = self.components.get(id) else {
+            return Ok(());
+        };
+        let started_at = Instant::now();
+        let digests = component
+            .files
+            .iter()
+            .map(|file| {
+                file.content
+                    .digest
+                    .as_deref()
+                    .with_context(|| format!("file {:?} has no digest", file.path))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.layers.write(&self.data_dir, &digests)?;
+        for (file, digest) in component.files.iter().zip(&digests) {
+            ensure!(
+                file.path
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_))),
+                "file path {:?} escapes the mount directory",
+                file.path
+            );
+            let mount_path = component.mount_dir.join(&file.path);
+            if let Some(parent) = mount_path.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create mount dir {parent:?}"))?;
+            }
+            fs::copy(self.data_dir.join(digest), &mount_path)
+                .with_context(|| format!("failed to mount file {:?}", file.path))?;
+            // The cache may be shared, so the copy is checked rather than the cached file
+            ensure!(
+                content_cache::is_intact(&mount_path, digest),
+                "mounted file {:?} does not match its digest {digest}",
+                file.path
+            );
+        }
+        log::info!(
+            " >>> mounted {} files of component {id:?} in {:?}",
+            digests.len(),
+            started_at.elapsed()
+        );
+        Ok(())
+    }
+}
+
+/// Files mounter writing the files of a component on its first mount, before mounting them with
+/// the mounter Spin would use.
+pub(crate) struct LazyFilesMounter<M> {
+    lazy_files: Arc<LazyFiles>,
+    inner: M,
+}
+
+impl<M> LazyFilesMounter<M> {
+    pub(crate) fn new(lazy_files: Arc<LazyFiles>, inner: M) -> Self {
+        Self { lazy_files, inner }
+    }
+}
+
+impl<M: FilesMounter> FilesMounter for LazyFilesMounter<M> {
+    fn mount_files(&self, app_component: &AppComponent, ctx: MountFilesContext) -> Result<()> {
+        // Spin mounts files synchronously while preparing an instance, so the preparing task
+        // waits for the copy, which runs on the blocking thread pool
+        let materialize = self.lazy_files.materialize(app_component.id());
+        futures::executor::block_on(materialize).with_context(|| {
+            format!(
+                "failed to mount files of component {:?}",
+                app_component.id()
+            )
+        })?;
+        self.inner.mount_files(app_component, ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use sha2::{Digest as _, Sha256};
+
+    use super::*;
+
+    fn digest(content: &[u8]) -> String {
+        format!("sha256:{:x}", Sha256::digest(content))
+    }
+
+    fn make_layer(media_type: &str, content: &[u8]) -> WasmLayer {
+        WasmLayer {
+            layer: content.to_vec(),
+            config: oci_spec::image::Descriptor::new(
+                oci_spec::image::MediaType::Other(media_type.to_string()),
+                content.len() as u64,
+                digest(content).parse().unwrap(),
+            ),
+        }
+    }
+
+    fn make_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        for (path, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, *content).unwrap();
+        }
+        let mut encoder = builder.into_inner().unwrap();
+        encoder.flush().unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn make_component(files: &[(&str, &[u8])]) -> LockedComponent {
+        let files = files
+            .iter()
+            .map(|(path, content)| serde_json::json!({ "content": { "digest": digest(content) }, "path": path }))
+            .collect::<Vec<_>>();
+        serde_json::from_value(serde_json::json!({
+            "id": "assets",
+            "source": { "content_type": "application/wasm", "content": {} },
+            "files": files,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn files_are_written_on_first_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("data");
+        fs::create_dir_all(&data_dir).unwrap();
+        let mount_dir = dir.path().join("assets");
+
+        let mut layers = DataLayers::default();
+        layers.add_data(
+            digest(b"index"),
+            make_layer(spin_oci::client::DATA_MEDIATYPE, b"index"),
+        );
+        let archive = make_archive(&[("style.css", b"style")]);
+        layers.add_archive(
+            digest(&archive),
+            make_layer(spin_oci::client::ARCHIVE_MEDIATYPE, &archive),
+        );
+        let mut lazy_files = LazyFiles::new(data_dir.clone(), layers);
+
+        let mut component =
+            make_component(&[("index.html", b"index"), ("css/style.css", b"style")]);
+        lazy_files
+            .defer_component_files(&mut component, mount_dir.clone())
+            .unwrap();
+        assert_eq!(component.files.len(), 1);
+        // Nothing is written to disk until the first mount
+        assert!(fs::read_dir(&data_dir).unwrap().next().is_none());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let lazy_files = Arc::new(lazy_files);
+        lazy_files.materialize("assets").await.unwrap();
+        assert_eq!(fs::read(mount_dir.join("index.html")).unwrap(), b"index");
+        assert_eq!(fs::read(mount_dir.join("css/style.css")).unwrap(), b"style");
+        // Written layers are released
+        assert!(lazy_files
+            .layers
+            .0
+            .iter()
+            .all(|layer| layer.layer.lock().unwrap().is_none()));
+
+        // Files are only written on the first mount
+        fs::remove_file(mount_dir.join("index.html")).unwrap();
+        lazy_files.materialize("assets").await.unwrap();
+        assert!(!mount_dir.join("index.html").exists());
+    }
+
+    #[tokio::test]
+    async fn files_cannot_escape_mount_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("data");
+        fs::create_dir_all(&data_dir).unwrap();
+        let mut layers = DataLayers::default();
+        layers.add_data(
+            digest(b"secret"),
+            make_layer(spin_oci::client::DATA_MEDIATYPE, b"secret"),
+        );
+        let mut lazy_files = LazyFiles::new(data_dir, layers);
+
+        let mut component = make_component(&[("../secret", b"secret")]);
+        lazy_files
+            .defer_component_files(&mut component, dir.path().join("assets"))
+            .unwrap();
+        assert!(Arc::new(lazy_files).materialize("assets").await.is_err());
+        assert!(!dir.path().join("secret").exists());
+    }
+
+    #[tokio::test]
+    async fn compressed_data_layers_are_decompressed_when_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("data");
+        fs::create_dir_all(&data_dir).unwrap();
+        let mount_dir = dir.path().join("assets");
+        let mut layers = DataLayers::default();
+        let compressed = zstd::encode_all(b"index".as_slice(), 0).unwrap();
+        layers.add_data(
+            digest(b"index"),
+            make_layer(constants::OCI_LAYER_MEDIA_TYPE_DATA_ZSTD, &compressed),
+        );
+        let mut lazy_files = LazyFiles::new(data_dir, layers);
+
+        let mut component = make_component(&[("index.html", b"index")]);
+        lazy_files
+            .defer_component_files(&mut component, mount_dir.clone())
+            .unwrap();
+        Arc::new(lazy_files).materialize("assets").await.unwrap();
+        assert_eq!(fs::read(mount_dir.join("index.html")).unwrap(), b"index");
+    }
+}
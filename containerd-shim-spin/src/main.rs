@@ -1,13 +1,29 @@
 use containerd_shim_wasm::shim::{Cli, Config};
 use engine::SpinShim;
 
+mod capabilities;
+mod compose;
 mod constants;
+mod content_cache;
 mod engine;
+mod engine_options;
+mod lazy_files;
+mod node_config;
+mod precompiled;
+mod preinit;
+mod signature;
 mod source;
 mod trigger;
 mod utils;
+mod watch;
+mod wkg;
 
 fn main() {
+    // Containers are forked from the shim process and inherit the node config, trust policy and
+    // records of precompiled artifacts it loaded
+    node_config::load();
+    signature::load();
+    precompiled::load();
     // Configure the shim to have only error level logging for performance improvements.
     let shim_config = Config {
         default_log_level: "error".to_string(),
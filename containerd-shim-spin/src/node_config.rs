@@ -0,0 +1,104 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::{constants, engine_options::EngineOptions, preinit::Preinitializer};
+
+/// Node config loaded by the shim process, or the error it failed to load with.
+static NODE_CONFIG: OnceLock<std::result::Result<NodeConfig, String>> = OnceLock::new();
+
+/// Node-level configuration of the shim, read from the JSON file at
+/// [`constants::SPIN_NODE_CONFIG_PATH_DEFAULT`]:
+///
+/// ```json
+/// { "engine": { "strategy": "winch" }, "preinit": {} }
+/// ```
+///
+/// Components are precompiled by the shim process, but executed by container processes whose
+/// environment is replaced by the one of their pod, so settings both must agree on cannot come
+/// from the environment. The shim process loads the file on startup, and the containers it
+/// creates inherit the loaded config, as they are forked from it. A missing file leaves every
+/// setting at its default.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct NodeConfig {
+    pub(crate) engine: EngineOptions,
+    /// Pre-initialization of wasm before it is precompiled, disabled unless set
+    pub(crate) preinit: Option<Preinitializer>,
+    /// Path of the trust policy images must satisfy, see
+    /// [`TrustPolicy`](crate::signature::TrustPolicy). Signature verification is disabled unless
+    /// set.
+    pub(crate) trust_policy: Option<PathBuf>,
+    /// Maximum size in bytes of the decompressed content of a compressed layer. Defaults to
+    /// [`constants::MAX_DECOMPRESSED_LAYER_SIZE_DEFAULT`].
+    pub(crate) max_decompressed_layer_size: Option<u64>,
+    /// Whether precompiled layers shipped by images are loaded as they are. They are native code
+    /// the shim cannot check, so by default they are recompiled from the original wasm the image
+    /// references, or rejected.
+    pub(crate) trust_image_precompiled: bool,
+}
+
+impl NodeConfig {
+    fn from_file(path: &Path) -> Result<Self> {
+        let json = match fs::read(path) {
+            Ok(json) => json,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read node config {path:?}"))
+            }
+        };
+        serde_json::from_slice(&json)
+            .with_context(|| format!("failed to parse node config {path:?}"))
+    }
+}
+
+/// Loads the node config, unless already loaded. Called by the shim process before it creates
+/// any container.
+pub(crate) fn load() {
+    NODE_CONFIG.get_or_init(|| {
+        let path = env::var_os(constants::SPIN_NODE_CONFIG_PATH_ENV)
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| constants::SPIN_NODE_CONFIG_PATH_DEFAULT.into());
+        NodeConfig::from_file(&path).map_err(|err| format!("{err:#}"))
+    });
+}
+
+/// Returns the node config, failing if it could not be loaded.
+pub(crate) fn get() -> Result<&'static NodeConfig> {
+    load();
+    NODE_CONFIG
+        .get()
+        .expect("node config is loaded")
+        .as_ref()
+        .map_err(|err| anyhow!("invalid node config: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine_options::Strategy;
+
+    #[test]
+    fn node_config_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        assert_eq!(NodeConfig::from_file(&path).unwrap(), NodeConfig::default());
+
+        fs::write(&path, r#"{ "engine": { "strategy": "winch" } }"#).unwrap();
+        let config = NodeConfig::from_file(&path).unwrap();
+        assert_eq!(config.engine.strategy, Some(Strategy::Winch));
+        assert!(config.preinit.is_none());
+
+        fs::write(&path, r#"{ "preinit": { "fuel": 1000 } }"#).unwrap();
+        assert!(NodeConfig::from_file(&path).unwrap().preinit.is_some());
+
+        fs::write(&path, r#"{ "engines": {} }"#).unwrap();
+        assert!(NodeConfig::from_file(&path).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Write as _},
+    path::PathBuf,
+    sync::{LazyLock, Mutex},
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest as _, Sha256};
+
+use crate::{constants, node_config};
+
+/// Records of the artifacts precompiled by the shim, see [`load`].
+static RECORDS: LazyLock<Records> = LazyLock::new(|| {
+    // Tests precompile layers too, and must not write to the node directory
+    if cfg!(test) {
+        Records::new(std::env::temp_dir().join("containerd-shim-spin-test-precompiled"))
+    } else {
+        Records::new(constants::SPIN_PRECOMPILED_RECORDS_DIR)
+    }
+});
+
+/// Loads the records of the artifacts precompiled by previous shim processes. Called by the shim
+/// process before it creates any container.
+///
+/// Runwasi substitutes the content of wasm layers with their precompiled artifact while keeping
+/// the original descriptor, so containers cannot check precompiled content against the layer
+/// digest. Instead, the shim process records the hash of every artifact it precompiles in
+/// [`constants::SPIN_PRECOMPILED_RECORDS_DIR`], and containers only load precompiled content
+/// whose hash is recorded for the layer. The directory is only written by shim processes on the
+/// node, and containers inherit the loaded records, as they are forked from the shim process.
+pub(crate) fn load() {
+    if let Err(err) = RECORDS.load() {
+        log::warn!("failed to load records of precompiled artifacts: {err:?}");
+    }
+}
+
+/// Records that `artifact` was precompiled by the shim from the layer with `digest`.
+pub(crate) fn record(artifact: &[u8], digest: &str) {
+    if let Err(err) = RECORDS.record(artifact, digest) {
+        // Containers created by this process still find the record in memory
+        log::warn!("failed to persist record of precompiled artifact for layer {digest}: {err:?}");
+    }
+}
+
+/// Returns whether `artifact` was precompiled by the shim from the layer with `digest`.
+pub(crate) fn is_recorded(artifact: &[u8], digest: &str) -> bool {
+    RECORDS.is_recorded(artifact, digest)
+}
+
+/// Returns whether the node config trusts precompiled layers shipped by images, which are loaded
+/// without being recorded as long as they match their layer digest.
+pub(crate) fn trust_image_precompiled() -> bool {
+    node_config::get().is_ok_and(|config| config.trust_image_precompiled)
+}
+
+/// Layer digests of precompiled artifacts, by SHA-256 hash of the artifact, persisted in `dir`
+/// as a file per artifact listing the digests.
+struct Records {
+    dir: PathBuf,
+    digests: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+impl Records {
+    fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            digests: Default::default(),
+        }
+    }
+
+    fn load(&self) -> Result<()> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).with_context(|| format!("failed to read {:?}", self.dir)),
+        };
+        let mut records = self.digests.lock().unwrap();
+        for entry in entries {
+            let path = entry?.path();
+            let Some(hash) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let digests =
+                fs::read_to_string(&path).with_context(|| format!("failed to read {path:?}"))?;
+            records
+                .entry(hash.to_string())
+                .or_default()
+                .extend(digests.lines().map(String::from));
+        }
+        Ok(())
+    }
+
+    fn record(&self, artifact: &[u8], digest: &str) -> Result<()> {
+        let hash = format!("{:x}", Sha256::digest(artifact));
+        let inserted = self
+            .digests
+            .lock()
+            .unwrap()
+            .entry(hash.clone())
+            .or_default()
+            .insert(digest.to_string());
+        if !inserted {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create {:?}", self.dir))?;
+        let path = self.dir.join(hash);
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{digest}"))
+            .with_context(|| format!("failed to write {path:?}"))
+    }
+
+    fn is_recorded(&self, artifact: &[u8], digest: &str) -> bool {
+        let hash = format!("{:x}", Sha256::digest(artifact));
+        let mut records = self.digests.lock().unwrap();
+        if records
+            .get(&hash)
+            .is_some_and(|digests| digests.contains(digest))
+        {
+            return true;
+        }
+        // Records written by other shim processes since the records were loaded
+        let Ok(persisted) = fs::read_to_string(self.dir.join(&hash)) else {
+            return false;
+        };
+        let digests = records.entry(hash).or_default();
+        digests.extend(persisted.lines().map(String::from));
+        digests.contains(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_artifacts_are_matched_by_hash_and_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let records = Records::new(dir.path());
+        let artifact = b"recorded artifact";
+        records.record(artifact, "sha256:layer").unwrap();
+        assert!(records.is_recorded(artifact, "sha256:layer"));
+        assert!(!records.is_recorded(artifact, "sha256:other"));
+        assert!(!records.is_recorded(b"tampered artifact", "sha256:layer"));
+
+        // Records persist for later shim processes
+        let records = Records::new(dir.path());
+        assert!(!records.is_recorded(artifact, "sha256:layer"));
+        records.load().unwrap();
+        assert!(records.is_recorded(artifact, "sha256:layer"));
+    }
+
+    #[test]
+    fn records_written_after_load_are_read_from_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let records = Records::new(dir.path());
+        records.load().unwrap();
+
+        // Recorded by another shim process
+        let artifact = b"recorded artifact";
+        Records::new(dir.path())
+            .record(artifact, "sha256:layer")
+            .unwrap();
+        assert!(records.is_recorded(artifact, "sha256:layer"));
+        assert!(!records.is_recorded(artifact, "sha256:other"));
+        assert!(!records.is_recorded(b"tampered artifact", "sha256:layer"));
+    }
+}
@@ -0,0 +1,193 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use wasmparser::{Parser, Payload};
+use wasmtime::{Store, StoreLimits, StoreLimitsBuilder};
+use wasmtime_wizer::Wizer;
+
+use crate::constants;
+
+/// Pre-initializes wasm at precompile time with [Wizer](wasmtime_wizer), configured by the
+/// `preinit` section of the [`NodeConfig`](crate::node_config::NodeConfig), e.g.:
+///
+/// ```json
+/// { "init_func": "wizer-initialize", "fuel": 1000000000, "max_memory_bytes": 536870912 }
+/// ```
+///
+/// Wizer runs the init export of the wasm once and snapshots the resulting memories and globals
+/// into a new binary, which is then precompiled, so that every instance starts already
+/// initialized. Pre-initialization is disabled unless the section is present.
+///
+/// The init export is guest code running in the shim process, so it runs sandboxed: every import
+/// traps when called, and execution is bounded by fuel and a memory limit. Wasm whose
+/// initialization needs host APIs, such as WASI, fails to pre-initialize.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Preinitializer {
+    /// Export run to pre-initialize the wasm. Defaults to
+    /// [`constants::SPIN_PRECOMPILE_PREINIT_COMPONENT_FUNC_DEFAULT`] for components and
+    /// [`constants::SPIN_PRECOMPILE_PREINIT_MODULE_FUNC_DEFAULT`] for modules.
+    init_func: Option<String>,
+    /// Fuel available to the init export
+    fuel: Option<u64>,
+    /// Maximum size in bytes of each linear memory during initialization
+    max_memory_bytes: Option<usize>,
+}
+
+impl Preinitializer {
+    /// Returns the pre-initialized snapshot of `wasm`, or `None` if `wasm` does not export the
+    /// init function.
+    pub(crate) fn preinitialize(&self, wasm: &[u8]) -> Result<Option<Vec<u8>>> {
+        let is_component = Parser::is_component(wasm);
+        let init_func = match (&self.init_func, is_component) {
+            (Some(init_func), _) => init_func.as_str(),
+            (None, true) => constants::SPIN_PRECOMPILE_PREINIT_COMPONENT_FUNC_DEFAULT,
+            (None, false) => constants::SPIN_PRECOMPILE_PREINIT_MODULE_FUNC_DEFAULT,
+        };
+        if !exports(wasm, init_func)? {
+            log::debug!("wasm does not export {init_func:?}; skipping pre-initialization");
+            return Ok(None);
+        }
+
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = wasmtime::Engine::new(&config)?;
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(
+                self.max_memory_bytes
+                    .unwrap_or(constants::SPIN_PRECOMPILE_PREINIT_MAX_MEMORY_DEFAULT),
+            )
+            .build();
+        let mut store = Store::new(&engine, limits);
+        store.limiter(|limits: &mut StoreLimits| limits);
+        store.set_fuel(
+            self.fuel
+                .unwrap_or(constants::SPIN_PRECOMPILE_PREINIT_FUEL_DEFAULT),
+        )?;
+
+        let mut wizer = Wizer::new();
+        wizer.init_func(init_func);
+        let snapshot = futures::executor::block_on(async {
+            if is_component {
+                wizer
+                    .run_component(&mut store, wasm, async |store, component| {
+                        let mut linker = wasmtime::component::Linker::new(store.engine());
+                        linker.define_unknown_imports_as_traps(component)?;
+                        linker.instantiate(store, component)
+                    })
+                    .await
+            } else {
+                wizer
+                    .run(&mut store, wasm, async |store, module| {
+                        let mut linker = wasmtime::Linker::new(store.engine());
+                        linker.define_unknown_imports_as_traps(module)?;
+                        linker.instantiate(store, module)
+                    })
+                    .await
+            }
+        })
+        .with_context(|| format!("failed to pre-initialize wasm by running {init_func:?}"))?;
+        log::info!("pre-initialized wasm by running {init_func:?}");
+        Ok(Some(snapshot))
+    }
+}
+
+// Returns whether the top-level module or component in `wasm` exports `name`
+fn exports(wasm: &[u8], name: &str) -> Result<bool> {
+    // Nested modules and components each start with a version payload and finish with an end
+    // payload, so only exports at depth 1 belong to the top-level module or component
+    let mut depth = 0;
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload.context("failed to parse wasm")? {
+            Payload::Version { .. } => depth += 1,
+            Payload::End(_) => depth -= 1,
+            Payload::ExportSection(reader) if depth == 1 => {
+                for export in reader {
+                    if export?.name == name {
+                        return Ok(true);
+                    }
+                }
+            }
+            Payload::ComponentExportSection(reader) if depth == 1 => {
+                for export in reader {
+                    if export?.name.0 == name {
+                        return Ok(true);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exports_only_checks_top_level() {
+        let module = wat::parse_str(r#"(module (func (export "wizer.initialize")))"#).unwrap();
+        assert!(exports(&module, "wizer.initialize").unwrap());
+        assert!(!exports(&module, "other").unwrap());
+
+        let component =
+            wat::parse_str(r#"(component (core module (func (export "wizer.initialize"))))"#)
+                .unwrap();
+        assert!(!exports(&component, "wizer.initialize").unwrap());
+    }
+
+    #[test]
+    fn wasm_without_init_export_is_not_preinitialized() {
+        let module = wat::parse_str("(module)").unwrap();
+        let preinitialized = Preinitializer::default().preinitialize(&module).unwrap();
+        assert!(preinitialized.is_none());
+    }
+
+    #[test]
+    fn preinitialize_snapshots_init_state() {
+        let module = wat::parse_str(
+            r#"(module
+                (global $g (mut i32) (i32.const 0))
+                (func (export "wizer.initialize") (global.set $g (i32.const 42)))
+                (func (export "get") (result i32) (global.get $g))
+            )"#,
+        )
+        .unwrap();
+        let snapshot = Preinitializer::default()
+            .preinitialize(&module)
+            .unwrap()
+            .expect("wasm was not pre-initialized");
+
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, &snapshot).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+        let get = instance
+            .get_typed_func::<(), i32>(&mut store, "get")
+            .unwrap();
+        assert_eq!(get.call(&mut store, ()).unwrap(), 42);
+    }
+
+    #[test]
+    fn preinitialize_is_sandboxed() {
+        // Imports trap instead of reaching the host
+        let module = wat::parse_str(
+            r#"(module
+                (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+                (func (export "wizer.initialize") (call $exit (i32.const 0)))
+            )"#,
+        )
+        .unwrap();
+        assert!(Preinitializer::default().preinitialize(&module).is_err());
+
+        // Execution is bounded by fuel
+        let module =
+            wat::parse_str(r#"(module (func (export "wizer.initialize") (loop $l (br $l))))"#)
+                .unwrap();
+        let preinit = Preinitializer {
+            fuel: Some(1_000_000),
+            ..Default::default()
+        };
+        assert!(preinit.preinitialize(&module).is_err());
+    }
+}
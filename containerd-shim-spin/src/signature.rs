@@ -0,0 +1,376 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use containerd_shim_wasm::sandbox::context::WasmLayer;
+use oci_spec::image::MediaType;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Verifier};
+use serde_json::Value;
+
+use crate::{constants, node_config};
+
+/// Trust policy loaded by the shim process, or the error it failed to load with.
+static TRUST_POLICY: OnceLock<std::result::Result<Option<TrustPolicy>, String>> = OnceLock::new();
+
+/// Loads the trust policy set by the `trust_policy` path of the
+/// [`NodeConfig`](crate::node_config::NodeConfig), unless already loaded. Called by the shim
+/// process before it creates any container, which inherit the loaded policy like the node config.
+pub(crate) fn load() {
+    TRUST_POLICY.get_or_init(|| {
+        let policy = node_config::get().and_then(|config| {
+            config
+                .trust_policy
+                .as_deref()
+                .map(TrustPolicy::from_file)
+                .transpose()
+        });
+        policy.map_err(|err| format!("{err:#}"))
+    });
+}
+
+/// Returns the trust policy, or `None` if signature verification is disabled. Fails if the policy
+/// could not be loaded, so that images are never run unverified because of an invalid policy.
+pub(crate) fn trust_policy() -> Result<Option<&'static TrustPolicy>> {
+    load();
+    TRUST_POLICY
+        .get()
+        .expect("trust policy is loaded")
+        .as_ref()
+        .map(Option::as_ref)
+        .map_err(|err| anyhow!("invalid trust policy: {err}"))
+}
+
+/// Node-local trust policy that images must satisfy before their layers are compiled or used.
+///
+/// Runwasi only gives the shim the layers of an image: not its manifest digest, nor its referrers,
+/// and the shim has no access to the registry. Cosign and notation signatures, which sign the
+/// manifest and are stored as separate artifacts, can therefore not be verified by the shim;
+/// verify them at admission time instead, e.g. with a Kubernetes policy controller. The shim
+/// verifies a signature over the digests of the layers, carried in the image itself: a signature
+/// layer of media type [`constants::OCI_LAYER_MEDIA_TYPE_SIGNATURE`] holds a base64 `payload`
+/// listing the digests of all other layers under `layers`, and base64 DER `signatures` of the
+/// payload, each with the `keyid` of the signing key:
+///
+/// ```json
+/// { "payload": "eyJsYXllcnMiOlsic2hhMjU2Oi4uLiJdfQ==", "signatures": [{ "keyid": "release", "sig": "MEUCIQ..." }] }
+/// ```
+///
+/// Layer contents are verified against their digests before the signature is checked. The policy
+/// file maps key ids to PEM public keys on the node, and sets whether failures are enforced or only
+/// audited:
+///
+/// ```json
+/// { "mode": "enforce", "trusted_keys": { "release": "/etc/containerd-shim-spin/keys/release.pub" } }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct TrustPolicy {
+    enforce: bool,
+    trusted_keys: BTreeMap<String, Vec<u8>>,
+}
+
+impl TrustPolicy {
+    fn from_file(path: &Path) -> Result<Self> {
+        let policy: Value = serde_json::from_slice(
+            &std::fs::read(path)
+                .with_context(|| format!("failed to read trust policy {path:?}"))?,
+        )
+        .with_context(|| format!("failed to parse trust policy {path:?}"))?;
+        let enforce = match policy.get("mode").and_then(Value::as_str) {
+            None | Some("enforce") => true,
+            Some("audit") => false,
+            Some(mode) => bail!(
+                "unknown mode {mode:?} in trust policy {path:?}, expected one of enforce, audit"
+            ),
+        };
+        let trusted_keys = policy
+            .get("trusted_keys")
+            .and_then(Value::as_object)
+            .with_context(|| format!("trust policy {path:?} has no trusted_keys"))?
+            .iter()
+            .map(|(keyid, key_path)| -> Result<(String, Vec<u8>)> {
+                let key_path = key_path
+                    .as_str()
+                    .map(PathBuf::from)
+                    .with_context(|| format!("trusted key {keyid:?} is not a path"))?;
+                let pem = std::fs::read(&key_path).with_context(|| {
+                    format!("failed to read trusted key {keyid:?} from {key_path:?}")
+                })?;
+                PKey::public_key_from_pem(&pem)
+                    .with_context(|| format!("trusted key {keyid:?} is not a PEM public key"))?;
+                Ok((keyid.clone(), pem))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        ensure!(
+            !trusted_keys.is_empty(),
+            "trust policy {path:?} has no trusted keys"
+        );
+        Ok(Self {
+            enforce,
+            trusted_keys,
+        })
+    }
+
+    /// Verifies the signature of the image made of `layers`, logging the outcome for auditing.
+    /// Fails if the image is not signed by a trusted key, unless the policy is in audit mode.
+    pub(crate) fn check(&self, layers: &[WasmLayer]) -> Result<()> {
+        let digests = signed_digests(layers);
+        match self.verify(layers, &digests) {
+            Ok(keyid) => {
+                log::info!(
+                    "signature verification succeeded for image layers {digests:?}: signed by trusted key {keyid:?}"
+                );
+                Ok(())
+            }
+            Err(err) if !self.enforce => {
+                log::warn!(
+                    "signature verification failed for image layers {digests:?}, allowed by audit mode: {err:#}"
+                );
+                Ok(())
+            }
+            Err(err) => {
+                log::error!(
+                    "signature verification failed for image layers {digests:?}, refusing to run: {err:#}"
+                );
+                Err(err.context("image signature verification failed"))
+            }
+        }
+    }
+
+    /// Checks whether file-based applications, which carry no signature, may run: they are
+    /// refused unless the policy is in audit mode.
+    pub(crate) fn check_file_app(&self, manifest_path: &Path) -> Result<()> {
+        if self.enforce {
+            log::error!(
+                "file-based application {manifest_path:?} cannot be verified, refusing to run"
+            );
+            bail!("image signature verification failed: file-based applications are not signed");
+        }
+        log::warn!(
+            "file-based application {manifest_path:?} cannot be verified, allowed by audit mode"
+        );
+        Ok(())
+    }
+
+    // Returns the id of the trusted key that signed the image
+    fn verify(&self, layers: &[WasmLayer], digests: &BTreeSet<String>) -> Result<String> {
+        let signature_layers = layers
+            .iter()
+            .filter(|layer| is_signature_layer(layer))
+            .collect::<Vec<_>>();
+        let [signature_layer] = signature_layers[..] else {
+            bail!(
+                "expected a single {} layer, found {}",
+                constants::OCI_LAYER_MEDIA_TYPE_SIGNATURE,
+                signature_layers.len()
+            );
+        };
+        let envelope: Value = serde_json::from_slice(&signature_layer.layer)
+            .context("failed to parse signature envelope")?;
+
+        let payload = envelope
+            .get("payload")
+            .and_then(Value::as_str)
+            .context("signature envelope has no payload")?;
+        let payload = STANDARD
+            .decode(payload)
+            .context("signature payload is not base64")?;
+        let signed_layers = serde_json::from_slice::<Value>(&payload)
+            .ok()
+            .and_then(|payload| {
+                payload
+                    .get("layers")?
+                    .as_array()?
+                    .iter()
+                    .map(|digest| digest.as_str().map(String::from))
+                    .collect::<Option<BTreeSet<_>>>()
+            })
+            .context("signature payload has no layers")?;
+        ensure!(
+            &signed_layers == digests,
+            "signature covers layers {signed_layers:?}, not the layers of the image"
+        );
+
+        let signatures = envelope
+            .get("signatures")
+            .and_then(Value::as_array)
+            .context("signature envelope has no signatures")?;
+        for signature in signatures {
+            let (Some(keyid), Some(sig)) = (
+                signature.get("keyid").and_then(Value::as_str),
+                signature.get("sig").and_then(Value::as_str),
+            ) else {
+                continue;
+            };
+            let Some(pem) = self.trusted_keys.get(keyid) else {
+                continue;
+            };
+            let sig = STANDARD
+                .decode(sig)
+                .with_context(|| format!("signature by key {keyid:?} is not base64"))?;
+            if verify_signature(pem, &payload, &sig)
+                .with_context(|| format!("failed to verify signature by key {keyid:?}"))?
+            {
+                return Ok(keyid.to_string());
+            }
+        }
+        bail!("no valid signature by a trusted key")
+    }
+}
+
+// Returns whether `sig` is a signature of `payload` by the PEM public key `pem`
+fn verify_signature(pem: &[u8], payload: &[u8], sig: &[u8]) -> Result<bool> {
+    let key = PKey::public_key_from_pem(pem)?;
+    // Ed25519 and Ed448 keys sign the message itself, other keys sign its SHA-256 digest
+    let mut verifier = match key.id() {
+        openssl::pkey::Id::ED25519 | openssl::pkey::Id::ED448 => {
+            Verifier::new_without_digest(&key)?
+        }
+        _ => Verifier::new(MessageDigest::sha256(), &key)?,
+    };
+    Ok(verifier.verify_oneshot(sig, payload)?)
+}
+
+fn is_signature_layer(layer: &WasmLayer) -> bool {
+    matches!(layer.config.media_type(), MediaType::Other(name) if name == constants::OCI_LAYER_MEDIA_TYPE_SIGNATURE)
+}
+
+// Returns the digests of the layers covered by the signature of an image
+fn signed_digests(layers: &[WasmLayer]) -> BTreeSet<String> {
+    layers
+        .iter()
+        .filter(|layer| !is_signature_layer(layer))
+        .map(|layer| layer.config.digest().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr as _;
+
+    use oci_spec::image::{Descriptor, Digest};
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+        pkey::Private,
+        sign::Signer,
+    };
+    use sha2::{Digest as _, Sha256};
+
+    use super::*;
+
+    // Builds a layer whose descriptor matches its content
+    fn make_layer(media_type: &str, data: Vec<u8>) -> WasmLayer {
+        let digest = format!("sha256:{:x}", Sha256::digest(&data));
+        WasmLayer {
+            config: Descriptor::new(
+                MediaType::Other(media_type.to_string()),
+                data.len() as u64,
+                Digest::from_str(&digest).unwrap(),
+            ),
+            layer: data,
+        }
+    }
+
+    fn generate_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn signed_image(key: &PKey<Private>, keyid: &str) -> Vec<WasmLayer> {
+        let wasm_layer = make_layer(constants::OCI_LAYER_MEDIA_TYPE_WASM, vec![]);
+        let payload =
+            serde_json::json!({ "layers": [wasm_layer.config.digest().to_string()] }).to_string();
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        let sig = signer.sign_oneshot_to_vec(payload.as_bytes()).unwrap();
+        let envelope = serde_json::json!({
+            "payload": STANDARD.encode(payload),
+            "signatures": [{ "keyid": keyid, "sig": STANDARD.encode(sig) }],
+        });
+        let signature_layer = make_layer(
+            constants::OCI_LAYER_MEDIA_TYPE_SIGNATURE,
+            envelope.to_string().into_bytes(),
+        );
+        vec![wasm_layer, signature_layer]
+    }
+
+    fn policy(key: &PKey<Private>, enforce: bool) -> TrustPolicy {
+        TrustPolicy {
+            enforce,
+            trusted_keys: [("release".to_string(), key.public_key_to_pem().unwrap())].into(),
+        }
+    }
+
+    #[test]
+    fn image_signed_by_trusted_key_is_accepted() {
+        let key = generate_key();
+        policy(&key, true)
+            .check(&signed_image(&key, "release"))
+            .unwrap();
+    }
+
+    #[test]
+    fn image_signed_by_untrusted_key_is_refused() {
+        let key = generate_key();
+        let other_key = generate_key();
+        let err = policy(&key, true)
+            .check(&signed_image(&other_key, "release"))
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("no valid signature by a trusted key"),
+            "unexpected error message: {err:#}"
+        );
+        // Audit mode only logs the failure
+        policy(&key, false)
+            .check(&signed_image(&other_key, "release"))
+            .unwrap();
+    }
+
+    #[test]
+    fn signature_must_cover_every_layer() {
+        let key = generate_key();
+        let mut layers = signed_image(&key, "release");
+        layers.push(make_layer(
+            spin_oci::client::DATA_MEDIATYPE,
+            b"test".to_vec(),
+        ));
+        assert!(policy(&key, true).check(&layers).is_err());
+        assert!(policy(&key, true).check(&layers[..1]).is_err());
+    }
+
+    #[test]
+    fn file_apps_are_refused_unless_audited() {
+        let key = generate_key();
+        assert!(policy(&key, true)
+            .check_file_app(Path::new("/spin.toml"))
+            .is_err());
+        policy(&key, false)
+            .check_file_app(Path::new("/spin.toml"))
+            .unwrap();
+    }
+
+    #[test]
+    fn trust_policy_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = generate_key();
+        let key_path = dir.path().join("release.pub");
+        std::fs::write(&key_path, key.public_key_to_pem().unwrap()).unwrap();
+        let policy_path = dir.path().join("policy.json");
+        std::fs::write(
+            &policy_path,
+            serde_json::json!({ "mode": "audit", "trusted_keys": { "release": key_path } })
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            TrustPolicy::from_file(&policy_path).unwrap(),
+            policy(&key, false)
+        );
+
+        std::fs::write(&policy_path, r#"{ "trusted_keys": {} }"#).unwrap();
+        assert!(TrustPolicy::from_file(&policy_path).is_err());
+    }
+}
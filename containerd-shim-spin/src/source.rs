@@ -1,4 +1,9 @@
-use std::{fs::File, io::Write, path::PathBuf};
+use std::{
+    collections::BTreeSet,
+    env,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
 use anyhow::{Context, Result};
 use containerd_shim_wasm::sandbox::context::RuntimeContext;
@@ -6,13 +11,21 @@ use log::info;
 use oci_spec::image::MediaType;
 use spin_app::locked::LockedApp;
 use spin_loader::{cache::Cache, FilesMountStrategy};
+use tempfile::TempDir;
 
-use crate::{constants, utils::handle_archive_layer};
+use crate::{
+    compose, constants, content_cache,
+    lazy_files::{lazy_data_layers, DataLayers, LazyFiles},
+    signature,
+    utils::{app_id, content_media_type, decompress_wasm_layer, scratch_dir, verify_layer},
+    wkg::{is_wit_package, select_component_layer, WkgLayer},
+};
 
 #[derive(Clone)]
 pub enum Source {
     File(PathBuf),
-    OciSpin,
+    /// Locked app of an OCI image, with its data layers not yet written to the cache
+    OciSpin(LockedApp, DataLayers),
     OciWkg(PathBuf),
 }
 
@@ -20,7 +33,7 @@ impl std::fmt::Debug for Source {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Source::File(path) => write!(f, "File({})", path.display()),
-            Source::OciSpin => write!(f, "OciSpin"),
+            Source::OciSpin(..) => write!(f, "OciSpin"),
             Source::OciWkg(path) => write!(f, "OciWkg({})", path.display()),
         }
     }
@@ -28,28 +41,48 @@ impl std::fmt::Debug for Source {
 
 impl Source {
     pub(crate) async fn from_ctx(ctx: &impl RuntimeContext, cache: &Cache) -> Result<Self> {
+        // Fails closed if the trust policy of the node could not be loaded
+        let trust_policy = signature::trust_policy()?;
         match ctx.entrypoint().source {
-            containerd_shim_wasm::sandbox::context::Source::File(_) => {
-                Ok(Source::File(constants::SPIN_MANIFEST_FILE_PATH.into()))
+            containerd_shim_wasm::sandbox::context::Source::File(entrypoint) => {
+                let manifest_path = manifest_path(&entrypoint);
+                if let Some(trust_policy) = trust_policy {
+                    trust_policy.check_file_app(&manifest_path)?;
+                }
+                info!(" >>> configuring spin application from manifest {manifest_path:?}");
+                Ok(Source::File(manifest_path))
             }
             containerd_shim_wasm::sandbox::context::Source::Oci(layers) => {
                 info!(" >>> configuring spin oci application {}", layers.len());
 
+                // Every layer is verified against its descriptor before any is used, and only
+                // then is the signature over the layer digests checked
                 for layer in layers {
                     log::debug!("<<< layer config: {:?}", layer.config);
+                    verify_layer(layer)?;
+                }
+                if let Some(trust_policy) = trust_policy {
+                    trust_policy.check(layers)?;
                 }
 
+                let mut locked_app = None;
+                let mut wkg_layers = Vec::new();
+                let mut data_layers = DataLayers::default();
                 for artifact in layers {
-                    match artifact.config.media_type() {
+                    // Compressed wasm layers are decompressed to be cached, while compressed data
+                    // layers are streamed when written
+                    let decompressed = decompress_wasm_layer(artifact)?;
+                    let artifact = decompressed.as_ref().unwrap_or(artifact);
+                    match &content_media_type(artifact) {
                         MediaType::Other(name)
                             if name == spin_oci::client::SPIN_APPLICATION_MEDIA_TYPE =>
                         {
-                            let path = PathBuf::from("/spin.json");
-                            log::info!("writing spin oci config to {path:?}");
-                            File::create(&path)
-                                .context("failed to create spin.json")?
-                                .write_all(&artifact.layer)
-                                .context("failed to write spin.json")?;
+                            log::info!(
+                                "<<< decoding locked app from layer {}",
+                                artifact.config.digest()
+                            );
+                            locked_app =
+                                compose::locked_app_from_layers(std::slice::from_ref(artifact))?;
                         }
                         MediaType::Other(name) if name == constants::OCI_LAYER_MEDIA_TYPE_WASM => {
                             log::info!(
@@ -57,9 +90,11 @@ impl Source {
                                 artifact.layer.len(),
                                 cache.manifests_dir()
                             );
-                            cache
-                                .write_wasm(&artifact.layer, &artifact.config.digest())
-                                .await?;
+                            content_cache::write_wasm(
+                                cache,
+                                &artifact.layer,
+                                artifact.config.digest(),
+                            )?;
                         }
                         MediaType::Other(name)
                             if name == constants::OCI_LAYER_MEDIA_TYPE_WASM_WKG =>
@@ -69,34 +104,34 @@ impl Source {
                                 artifact.layer.len(),
                                 cache.manifests_dir()
                             );
-                            cache
-                                .write_wasm(&artifact.layer, &artifact.config.digest())
-                                .await?;
-                            if layers.len() != 1 {
-                                anyhow::bail!(
-                                    "expected single layer in OCI package, found {} layers",
-                                    layers.len()
-                                );
-                            }
-                            return Ok(Source::OciWkg(cache.wasm_path(artifact.config.digest())));
+                            content_cache::write_wasm(
+                                cache,
+                                &artifact.layer,
+                                artifact.config.digest(),
+                            )?;
+                            wkg_layers.push(WkgLayer {
+                                digest: artifact.config.digest().to_string(),
+                                wit_package: is_wit_package(&artifact.layer).with_context(
+                                    || {
+                                        format!(
+                                            "failed to parse wasm layer {}",
+                                            artifact.config.digest()
+                                        )
+                                    },
+                                )?,
+                            });
                         }
+                        // Data layers are held rather than written to disk until `to_locked_app`
+                        // resolves the files of components, or until components are first mounted
                         MediaType::Other(name) if name == spin_oci::client::DATA_MEDIATYPE => {
-                            log::debug!(
-                                "<<< writing data layer to cache, near {:?}",
-                                cache.manifests_dir()
-                            );
-                            cache
-                                .write_data(&artifact.layer, &artifact.config.digest())
-                                .await?;
+                            data_layers
+                                .add_data(artifact.config.digest().to_string(), artifact.clone());
                         }
                         MediaType::Other(name) if name == spin_oci::client::ARCHIVE_MEDIATYPE => {
-                            log::debug!(
-                                "<<< writing archive layer and unpacking contents to cache, near {:?}",
-                                cache.manifests_dir()
+                            data_layers.add_archive(
+                                artifact.config.digest().to_string(),
+                                artifact.clone(),
                             );
-                            handle_archive_layer(cache, &artifact.layer, &artifact.config.digest())
-                                .await
-                                .context("unable to unpack archive layer")?;
                         }
                         _ => {
                             log::debug!(
@@ -106,35 +141,83 @@ impl Source {
                         }
                     }
                 }
-                Ok(Source::OciSpin)
+                if !wkg_layers.is_empty() {
+                    let layer = select_component_layer(&wkg_layers)?;
+                    let wasm_path = cache.wasm_path(&layer.digest);
+                    content_cache::verify_wasm(&wasm_path, &layer.digest)?;
+                    return Ok(Source::OciWkg(wasm_path));
+                }
+                let locked_app = locked_app.with_context(|| {
+                    format!(
+                        "image has no locked app layer of media type {}",
+                        spin_oci::client::SPIN_APPLICATION_MEDIA_TYPE
+                    )
+                })?;
+                Ok(Source::OciSpin(locked_app, data_layers))
             }
         }
     }
 
-    pub(crate) async fn to_locked_app(&self, cache: &Cache) -> Result<LockedApp> {
+    /// Resolves the locked app of the source, with the files of its components deferred to their
+    /// first mount when data layers are written lazily. Files copied with the `copy` files mount
+    /// strategy are removed when the returned directory is dropped.
+    pub(crate) async fn to_locked_app(
+        &self,
+        cache: &Cache,
+    ) -> Result<(LockedApp, Option<Arc<LazyFiles>>, Option<TempDir>)> {
+        let mut lazy_files = None;
+        let mut files_dir = None;
         let locked_app = match self {
             Source::File(source) => {
-                // TODO: This should be configurable, see https://github.com/deislabs/containerd-wasm-shims/issues/166
-                // TODO: ^^ Move aforementioned issue to this repo
-                let files_mount_strategy = FilesMountStrategy::Direct;
-                spin_loader::from_file(&source, files_mount_strategy, None).await
+                let (files_mount_strategy, copied_files_dir) = files_mount_strategy()?;
+                files_dir = copied_files_dir;
+                // Component sources and files are resolved relative to the directory of the manifest
+                spin_loader::from_file(&source, files_mount_strategy, None)
+                    .await
+                    .with_context(|| format!("failed to load manifest {source:?}"))
             }
-            Source::OciSpin => {
-                let working_dir = PathBuf::from("/");
-                let loader = spin_oci::OciLoader::new(working_dir);
+            Source::OciSpin(locked_app, data_layers) => {
+                // Component files are copied under the scratch directory, which is writable even
+                // with a read-only root filesystem
+                let working_dir = scratch_dir();
+                let loader = spin_oci::OciLoader::new(&working_dir);
+                let data_dir = content_cache::data_dir(cache)?;
+                let mut deferred = lazy_data_layers()
+                    .then(|| LazyFiles::new(data_dir.clone(), data_layers.clone()));
+                if deferred.is_none() {
+                    data_layers.write_all(&data_dir)?;
+                }
 
-                let locked_content = tokio::fs::read("/spin.json")
-                    .await
-                    .context("failed to read from \"/spin.json\"")?;
-                let mut locked_app = LockedApp::from_json(&locked_content)
-                    .context("failed to decode locked app from \"/spin.json\"")?;
+                let mut locked_app = locked_app.clone();
                 for component in &mut locked_app.components {
+                    // Deferred files are left out of resolution, which copies them to the mount dir
+                    let deferred_files = deferred
+                        .is_some()
+                        .then(|| std::mem::take(&mut component.files));
                     loader
                         .resolve_component_content_refs(component, cache)
                         .await
                         .with_context(|| {
                             format!("failed to resolve content for component {:?}", component.id)
                         })?;
+                    for source in std::iter::once(&component.source).chain(
+                        component
+                            .dependencies
+                            .values()
+                            .map(|dependency| &dependency.source),
+                    ) {
+                        content_cache::verify_source(source)?;
+                    }
+                    if let (Some(lazy_files), Some(files)) = (&mut deferred, deferred_files) {
+                        component.files = files;
+                        // Spin's loader mounts files from the same dir
+                        let mount_dir = working_dir.join("assets").join(&component.id);
+                        lazy_files.defer_component_files(component, mount_dir)?;
+                    }
+                }
+                if let Some(deferred) = deferred.filter(|lazy_files| !lazy_files.is_empty()) {
+                    info!(" >>> deferring component files to their first mount");
+                    lazy_files = Some(Arc::new(deferred));
                 }
                 Ok(locked_app)
             }
@@ -142,7 +225,98 @@ impl Source {
                 .await
                 .with_context(|| format!("Failed to load component from {wasm_path:?}")),
         }?;
-        Ok(locked_app)
+        Ok((locked_app, lazy_files, files_dir))
+    }
+}
+
+/// Directories of applications emptied by this process, which only empties them when first
+/// loading the application, so that reloads keep the files of the running version.
+static CLEARED_APP_FILES_DIRS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Returns the files mount strategy configured by [`constants::SPIN_FILES_MOUNT_STRATEGY_ENV`],
+/// along with the directory files are copied into with the `copy` strategy.
+///
+/// The `copy` strategy copies files into a directory of the application under the scratch
+/// directory, so that containers sharing the scratch directory do not see each other's files.
+/// Every load copies into a fresh directory of its own, removed when dropped, so that a reload
+/// does not clear the files of the running version. The directory of the application is emptied
+/// when first loading the application, so that restarts do not leak the copies of a container
+/// that did not exit cleanly.
+fn files_mount_strategy() -> Result<(FilesMountStrategy, Option<TempDir>)> {
+    match env::var(constants::SPIN_FILES_MOUNT_STRATEGY_ENV).as_deref() {
+        Err(_) | Ok("") | Ok("direct") => Ok((FilesMountStrategy::Direct, None)),
+        Ok("copy") => {
+            let app_files_dir = env::var_os(constants::SPIN_FILES_MOUNT_DIR_ENV)
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+                .unwrap_or_else(|| scratch_dir().join(constants::SPIN_FILES_MOUNT_DIR_DEFAULT))
+                .join(app_id());
+            if CLEARED_APP_FILES_DIRS
+                .lock()
+                .unwrap()
+                .insert(app_files_dir.clone())
+            {
+                match std::fs::remove_dir_all(&app_files_dir) {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(err) => {
+                        return Err(err).with_context(|| {
+                            format!("failed to clear files dir {app_files_dir:?}")
+                        })
+                    }
+                }
+            }
+            std::fs::create_dir_all(&app_files_dir)
+                .with_context(|| format!("failed to create files dir {app_files_dir:?}"))?;
+            let files_dir = tempfile::Builder::new()
+                .prefix("version-")
+                .tempdir_in(&app_files_dir)
+                .with_context(|| format!("failed to create files dir in {app_files_dir:?}"))?;
+            info!(" >>> copying application files to {:?}", files_dir.path());
+            Ok((
+                FilesMountStrategy::Copy(files_dir.path().to_path_buf()),
+                Some(files_dir),
+            ))
+        }
+        Ok(strategy) => anyhow::bail!(
+            "unknown files mount strategy {strategy:?} in {}, expected one of direct, copy",
+            constants::SPIN_FILES_MOUNT_STRATEGY_ENV
+        ),
+    }
+}
+
+/// Returns whether files are mounted read-only into every component, see
+/// [`constants::SPIN_FILES_READ_ONLY_ENV`].
+pub(crate) fn files_read_only() -> bool {
+    env::var(constants::SPIN_FILES_READ_ONLY_ENV).is_ok_and(|read_only| read_only == "true")
+}
+
+/// Returns the manifest path of a file-based application: the path set by
+/// [`constants::SPIN_MANIFEST_PATH_ENV`], else the container entrypoint, else
+/// [`constants::SPIN_MANIFEST_FILE_PATH`].
+///
+/// Only a `.toml` file or a directory, which resolves to the `spin.toml` it contains, is taken
+/// as the manifest; any other entrypoint, such as the `.wasm` module some images set, falls back
+/// to [`constants::SPIN_MANIFEST_FILE_PATH`]. Relative paths are resolved from the root of the
+/// container, so that the default `/` entrypoint keeps resolving to `/spin.toml`.
+fn manifest_path(entrypoint: &Path) -> PathBuf {
+    let path = env::var_os(constants::SPIN_MANIFEST_PATH_ENV)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| (!entrypoint.as_os_str().is_empty()).then(|| entrypoint.to_path_buf()));
+    let Some(path) = path else {
+        return constants::SPIN_MANIFEST_FILE_PATH.into();
+    };
+    let path = Path::new("/").join(path);
+    if path.is_dir() {
+        path.join(constants::SPIN_MANIFEST_FILE_NAME)
+    } else if path
+        .extension()
+        .is_some_and(|extension| extension == "toml")
+    {
+        path
+    } else {
+        constants::SPIN_MANIFEST_FILE_PATH.into()
     }
 }
 
@@ -155,25 +329,23 @@ mod tests {
 
     use containerd_shim_wasm::sandbox::context::{Entrypoint, RuntimeContext, WasmLayer};
     use oci_spec::image::{Descriptor, Digest, MediaType};
+    use sha2::Digest as _;
     use spin_loader::cache::Cache;
     use tempfile::{tempdir, TempDir};
 
     use super::*;
     use crate::constants;
 
-    // A digest shared across helpers; only one wasm is written per cache in each test so
-    // collisions are not a concern.
-    const TEST_DIGEST: &str =
-        "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
-
+    // Builds a layer whose descriptor matches its content
     fn make_layer(media_type: &str, data: Vec<u8>) -> WasmLayer {
+        let digest = format!("sha256:{:x}", sha2::Sha256::digest(&data));
         WasmLayer {
-            layer: data,
             config: Descriptor::new(
                 MediaType::Other(media_type.to_string()),
-                1024,
-                Digest::from_str(TEST_DIGEST).unwrap(),
+                data.len() as u64,
+                Digest::from_str(&digest).unwrap(),
             ),
+            layer: data,
         }
     }
 
@@ -231,16 +403,19 @@ mod tests {
 
     // ── from_ctx tests ───────────────────────────────────────────────────────
 
-    /// A File source always resolves to the well-known spin.toml manifest path,
-    /// regardless of what path the container spec provided.
+    /// A File source whose entrypoint is not a manifest, such as a wasm module, falls back to the
+    /// well-known spin.toml manifest path.
     #[tokio::test]
     async fn from_ctx_file_source_returns_spin_manifest_path() {
         let ctx = MockFileContext;
         let (cache, _dir) = make_cache().await;
 
-        let source = Source::from_ctx(&ctx, &cache)
-            .await
-            .expect("from_ctx failed");
+        let source = temp_env::async_with_vars(
+            [(constants::SPIN_MANIFEST_PATH_ENV, None::<&str>)],
+            Source::from_ctx(&ctx, &cache),
+        )
+        .await
+        .expect("from_ctx failed");
 
         assert!(
             matches!(source.clone(), Source::File(path) if path == Path::new(constants::SPIN_MANIFEST_FILE_PATH)),
@@ -250,26 +425,105 @@ mod tests {
         );
     }
 
-    /// An OCI image with no layers resolves to OciSpin (the loop body is simply
-    /// never entered).
+    /// The default `/` entrypoint and an unset entrypoint resolve to the well-known spin.toml
+    /// manifest path, while directories resolve to the spin.toml they contain.
+    #[test]
+    fn manifest_path_from_entrypoint() {
+        let dir = tempdir().unwrap();
+        temp_env::with_var_unset(constants::SPIN_MANIFEST_PATH_ENV, || {
+            assert_eq!(
+                manifest_path(Path::new("/")),
+                Path::new(constants::SPIN_MANIFEST_FILE_PATH)
+            );
+            assert_eq!(
+                manifest_path(Path::new("")),
+                Path::new(constants::SPIN_MANIFEST_FILE_PATH)
+            );
+            assert_eq!(manifest_path(dir.path()), dir.path().join("spin.toml"));
+            assert_eq!(
+                manifest_path(Path::new("apps/hello/spin.toml")),
+                Path::new("/apps/hello/spin.toml")
+            );
+            // Entrypoints that are not a manifest, such as a wasm module, are ignored
+            assert_eq!(
+                manifest_path(Path::new("/app.wasm")),
+                Path::new(constants::SPIN_MANIFEST_FILE_PATH)
+            );
+        });
+    }
+
+    /// The manifest path set in the environment takes precedence over the entrypoint.
+    #[test]
+    fn manifest_path_from_env() {
+        temp_env::with_var(
+            constants::SPIN_MANIFEST_PATH_ENV,
+            Some("/apps/hello/spin.toml"),
+            || {
+                assert_eq!(
+                    manifest_path(Path::new("/")),
+                    Path::new("/apps/hello/spin.toml")
+                );
+            },
+        );
+    }
+
+    const TEST_APP_JSON: &str = r#"{ "spin_lock_version": 1, "components": [], "triggers": [] }"#;
+
+    fn make_app_layer() -> WasmLayer {
+        make_layer(
+            spin_oci::client::SPIN_APPLICATION_MEDIA_TYPE,
+            TEST_APP_JSON.as_bytes().to_vec(),
+        )
+    }
+
+    /// An OCI image without a locked app layer is rejected, naming the expected media type.
     #[tokio::test]
-    async fn from_ctx_oci_empty_layers_returns_oci_spin() {
+    async fn from_ctx_oci_without_app_layer_returns_error() {
         let ctx = MockOciContext { layers: vec![] };
         let (cache, _dir) = make_cache().await;
 
-        let source = Source::from_ctx(&ctx, &cache)
+        let err = Source::from_ctx(&ctx, &cache)
             .await
-            .expect("from_ctx failed");
+            .unwrap_err()
+            .to_string();
 
-        assert!(matches!(source, Source::OciSpin));
+        assert!(
+            err.contains(spin_oci::client::SPIN_APPLICATION_MEDIA_TYPE),
+            "unexpected error message: {err}"
+        );
+    }
+
+    /// A malformed locked app layer is rejected, naming the layer.
+    #[tokio::test]
+    async fn from_ctx_oci_malformed_app_layer_returns_error() {
+        let ctx = MockOciContext {
+            layers: vec![make_layer(
+                spin_oci::client::SPIN_APPLICATION_MEDIA_TYPE,
+                b"not json".to_vec(),
+            )],
+        };
+        let (cache, _dir) = make_cache().await;
+
+        let err = Source::from_ctx(&ctx, &cache)
+            .await
+            .unwrap_err()
+            .to_string();
+
+        assert!(
+            err.contains(&ctx.layers[0].config.digest().to_string()),
+            "unexpected error message: {err}"
+        );
     }
 
     /// Layers with unrecognised media types are silently skipped and the result
-    /// is still OciSpin.
+    /// is still OciSpin, carrying the locked app of the image.
     #[tokio::test]
     async fn from_ctx_oci_unknown_media_type_returns_oci_spin() {
         let ctx = MockOciContext {
-            layers: vec![make_layer("application/unknown+type", vec![])],
+            layers: vec![
+                make_layer("application/unknown+type", vec![]),
+                make_app_layer(),
+            ],
         };
         let (cache, _dir) = make_cache().await;
 
@@ -277,7 +531,10 @@ mod tests {
             .await
             .expect("from_ctx failed");
 
-        assert!(matches!(source, Source::OciSpin));
+        let Source::OciSpin(locked_app, _) = source else {
+            panic!("expected Source::OciSpin, got {:?}", source);
+        };
+        assert!(locked_app.components.is_empty());
     }
 
     /// A standard Wasm layer (`application/vnd.wasm.content.layer.v1+wasm`) is
@@ -285,11 +542,14 @@ mod tests {
     #[tokio::test]
     async fn from_ctx_oci_wasm_layer_writes_to_cache_and_returns_oci_spin() {
         let ctx = MockOciContext {
-            layers: vec![make_layer(
-                constants::OCI_LAYER_MEDIA_TYPE_WASM,
-                // Minimal valid wasm binary header (magic + version)
-                vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00],
-            )],
+            layers: vec![
+                make_layer(
+                    constants::OCI_LAYER_MEDIA_TYPE_WASM,
+                    // Minimal valid wasm binary header (magic + version)
+                    vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00],
+                ),
+                make_app_layer(),
+            ],
         };
         let (cache, _dir) = make_cache().await;
 
@@ -297,9 +557,9 @@ mod tests {
             .await
             .expect("from_ctx failed");
 
-        assert!(matches!(source, Source::OciSpin));
+        assert!(matches!(source, Source::OciSpin(..)));
         // Check that it was written to cache
-        let expected_path = cache.wasm_path(Digest::from_str(TEST_DIGEST).unwrap());
+        let expected_path = cache.wasm_path(ctx.layers[0].config.digest());
         assert!(
             expected_path.exists(),
             "wasm not written to cache at expected path {:?}",
@@ -307,20 +567,78 @@ mod tests {
         );
     }
 
-    /// A data layer (`application/vnd.spin.content.bytes.v1`) is written to
-    /// cache and the source resolves to OciSpin.
+    /// A layer whose content does not hash to its descriptor digest fails the container
+    /// before anything is written to the cache.
     #[tokio::test]
-    async fn from_ctx_oci_data_layer_writes_to_cache_and_returns_oci_spin() {
+    async fn from_ctx_oci_corrupted_layer_returns_error() {
+        let mut layer = make_layer(constants::OCI_LAYER_MEDIA_TYPE_WASM, b"\0asm".to_vec());
+        layer.layer = b"\0bad".to_vec();
         let ctx = MockOciContext {
-            layers: vec![make_layer(spin_oci::client::DATA_MEDIATYPE, vec![])],
+            layers: vec![layer],
         };
         let (cache, _dir) = make_cache().await;
 
-        let source = Source::from_ctx(&ctx, &cache)
+        let err = Source::from_ctx(&ctx, &cache)
             .await
-            .expect("from_ctx failed");
+            .unwrap_err()
+            .to_string();
+
+        assert!(
+            err.contains(&ctx.layers[0].config.digest().to_string())
+                && err.contains(constants::OCI_LAYER_MEDIA_TYPE_WASM),
+            "unexpected error message: {err}"
+        );
+        assert!(!cache.wasm_path(ctx.layers[0].config.digest()).exists());
+    }
+
+    /// Precompiled content that the shim did not record as precompiled from the layer it
+    /// substitutes is rejected.
+    #[tokio::test]
+    async fn from_ctx_oci_unrecorded_precompiled_layer_returns_error() {
+        let mut layer = make_layer(constants::OCI_LAYER_MEDIA_TYPE_WASM, b"\0asm".to_vec());
+        layer.layer = wasmtime::Engine::default()
+            .precompile_component(&wat::parse_str("(component)").unwrap())
+            .unwrap();
+        let ctx = MockOciContext {
+            layers: vec![layer],
+        };
+        let (cache, _dir) = make_cache().await;
+
+        let err = Source::from_ctx(&ctx, &cache)
+            .await
+            .unwrap_err()
+            .to_string();
 
-        assert!(matches!(source, Source::OciSpin));
+        assert!(
+            err.contains("was not precompiled by the shim"),
+            "unexpected error message: {err}"
+        );
+        assert!(!cache.wasm_path(ctx.layers[0].config.digest()).exists());
+    }
+
+    /// A data layer (`application/vnd.spin.content.bytes.v1`) is only indexed, with nothing
+    /// written to disk, and the source resolves to OciSpin.
+    #[tokio::test]
+    async fn from_ctx_oci_data_layer_is_indexed_and_returns_oci_spin() {
+        let ctx = MockOciContext {
+            layers: vec![
+                make_layer(spin_oci::client::DATA_MEDIATYPE, vec![]),
+                make_app_layer(),
+            ],
+        };
+        let (cache, dir) = make_cache().await;
+        let scratch_dir = dir.path().join("scratch");
+
+        let source = temp_env::async_with_vars(
+            [(constants::SPIN_SCRATCH_DIR_ENV, Some(&scratch_dir))],
+            Source::from_ctx(&ctx, &cache),
+        )
+        .await
+        .expect("from_ctx failed");
+
+        assert!(matches!(source, Source::OciSpin(..)));
+        assert!(!cache.data_path(ctx.layers[0].config.digest()).exists());
+        assert!(!scratch_dir.exists());
     }
 
     /// A single `application/wasm` (wkg) layer is written to cache and the
@@ -353,29 +671,119 @@ mod tests {
         );
     }
 
-    /// More than one `application/wasm` (wkg) layer is not a valid OCI package
-    /// – the shim should return an error.
+    /// WIT packages next to the component of a wasm package are tolerated, and the source
+    /// resolves to the component layer.
     #[tokio::test]
-    async fn from_ctx_oci_wkg_multiple_layers_returns_error() {
-        let layer = make_layer(
-            constants::OCI_LAYER_MEDIA_TYPE_WASM_WKG,
-            vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00],
-        );
+    async fn from_ctx_oci_wkg_skips_wit_package_layers() {
+        let package = wat::parse_str(
+            r#"(component (type $iface (instance)) (export "test:pkg/iface" (type $iface)))"#,
+        )
+        .unwrap();
+        let component = wat::parse_str("(component)").unwrap();
         let ctx = MockOciContext {
-            layers: vec![layer.clone(), layer],
+            layers: vec![
+                make_layer(constants::OCI_LAYER_MEDIA_TYPE_WASM_WKG, package),
+                make_layer(constants::OCI_LAYER_MEDIA_TYPE_WASM_WKG, component),
+            ],
+        };
+        let (cache, _dir) = make_cache().await;
+
+        let source = Source::from_ctx(&ctx, &cache)
+            .await
+            .expect("from_ctx failed");
+
+        let Source::OciWkg(wasm_path) = source else {
+            panic!("expected Source::OciWkg, got {:?}", source);
+        };
+        assert_eq!(wasm_path, cache.wasm_path(ctx.layers[1].config.digest()));
+    }
+
+    /// More than one runnable component in a wasm package is ambiguous – the shim should
+    /// return an error.
+    #[tokio::test]
+    async fn from_ctx_oci_wkg_multiple_components_returns_error() {
+        let ctx = MockOciContext {
+            layers: vec![
+                make_layer(
+                    constants::OCI_LAYER_MEDIA_TYPE_WASM_WKG,
+                    wat::parse_str("(component)").unwrap(),
+                ),
+                make_layer(
+                    constants::OCI_LAYER_MEDIA_TYPE_WASM_WKG,
+                    wat::parse_str("(component (core module))").unwrap(),
+                ),
+            ],
         };
         let (cache, _dir) = make_cache().await;
 
         let result = Source::from_ctx(&ctx, &cache).await;
 
-        assert!(result.is_err(), "expected an error for multiple wkg layers");
+        assert!(result.is_err(), "expected an error for multiple components");
         let err = result.unwrap_err().to_string();
         assert!(
-            err.contains("expected single layer"),
+            err.contains("expected a single runnable component"),
             "unexpected error message: {err}"
         );
     }
 
+    // ── files mount tests ────────────────────────────────────────────────────
+
+    #[test]
+    fn files_mount_strategy_from_env() {
+        let dir = tempdir().unwrap();
+        temp_env::with_vars(
+            [
+                (constants::SPIN_FILES_MOUNT_STRATEGY_ENV, Some("copy")),
+                (
+                    constants::SPIN_FILES_MOUNT_DIR_ENV,
+                    Some(dir.path().to_str().unwrap()),
+                ),
+            ],
+            || {
+                let app_files_dir = dir.path().join(app_id());
+                // Copies left by a previous container of the app are removed on the first load
+                std::fs::create_dir_all(app_files_dir.join("stale")).unwrap();
+                let (FilesMountStrategy::Copy(files_dir), Some(guard)) =
+                    files_mount_strategy().unwrap()
+                else {
+                    panic!("expected the copy files mount strategy");
+                };
+                assert!(files_dir.is_dir() && files_dir.starts_with(&app_files_dir));
+                assert!(!app_files_dir.join("stale").exists());
+                std::fs::write(files_dir.join("index.html"), "index").unwrap();
+
+                // Reloads copy into a fresh directory, keeping the files of the running version
+                let (FilesMountStrategy::Copy(reloaded_dir), Some(reloaded_guard)) =
+                    files_mount_strategy().unwrap()
+                else {
+                    panic!("expected the copy files mount strategy");
+                };
+                assert_ne!(reloaded_dir, files_dir);
+                assert!(files_dir.join("index.html").exists());
+
+                // The files of a version are removed once it is dropped
+                drop(guard);
+                assert!(!files_dir.exists());
+                assert!(reloaded_dir.is_dir());
+                drop(reloaded_guard);
+                assert!(!reloaded_dir.exists());
+            },
+        );
+        temp_env::with_var_unset(constants::SPIN_FILES_MOUNT_STRATEGY_ENV, || {
+            assert!(matches!(
+                files_mount_strategy().unwrap(),
+                (FilesMountStrategy::Direct, None)
+            ));
+        });
+        temp_env::with_var(
+            constants::SPIN_FILES_MOUNT_STRATEGY_ENV,
+            Some("symlink"),
+            || {
+                assert!(files_mount_strategy().is_err());
+            },
+        );
+    }
+
     // ── to_locked_app tests ──────────────────────────────────────────────────
 
     /// Missing manifest file produces a load error.
@@ -389,22 +797,73 @@ mod tests {
         assert!(result.is_err(), "expected an error for missing spin.toml");
     }
 
-    /// If `/spin.json` is absent the OciSpin path must return an error.
-    /// The test skips if `/spin.json` happens to exist in the test environment.
+    /// The OciSpin path resolves the locked app carried by the source, without touching the
+    /// filesystem root.
     #[tokio::test]
-    async fn to_locked_app_oci_spin_without_spin_json_returns_error() {
-        if std::path::Path::new("/spin.json").exists() {
-            // /spin.json exists in this environment; skip to avoid a false result.
-            return;
-        }
-        let source = Source::OciSpin;
+    async fn to_locked_app_oci_spin_returns_carried_app() {
+        let source = Source::OciSpin(
+            LockedApp::from_json(TEST_APP_JSON.as_bytes()).unwrap(),
+            DataLayers::default(),
+        );
         let (cache, _dir) = make_cache().await;
 
-        let result = source.to_locked_app(&cache).await;
+        let (locked_app, ..) = source
+            .to_locked_app(&cache)
+            .await
+            .expect("to_locked_app failed");
 
-        assert!(
-            result.is_err(),
-            "expected an error when /spin.json is absent"
+        assert!(locked_app.components.is_empty());
+    }
+
+    /// Component files of an OCI app are mounted from a directory under the scratch directory
+    /// rather than the container root, which may be read-only.
+    #[tokio::test]
+    async fn to_locked_app_oci_spin_mounts_files_under_scratch_dir() {
+        let (cache, dir) = make_cache().await;
+        let scratch_dir = dir.path().join("scratch");
+        std::fs::create_dir_all(&scratch_dir).unwrap();
+        let wasm = wat::parse_str("(component)").unwrap();
+        let wasm_digest = format!("sha256:{:x}", sha2::Sha256::digest(&wasm));
+        content_cache::write_wasm(&cache, &wasm, &wasm_digest).unwrap();
+        let locked_app = LockedApp::from_json(
+            serde_json::json!({
+                "spin_lock_version": 1,
+                "triggers": [],
+                "components": [{
+                    "id": "assets",
+                    "source": {
+                        "content_type": "application/wasm",
+                        "content": { "digest": wasm_digest },
+                    },
+                    "files": [{
+                        "content": { "digest": format!("sha256:{:x}", sha2::Sha256::digest(b"index")) },
+                        "path": "index.html",
+                    }],
+                }],
+            })
+            .to_string()
+            .as_bytes(),
+        )
+        .unwrap();
+        let source = Source::OciSpin(locked_app, DataLayers::default());
+
+        let (locked_app, lazy_files, _) = temp_env::async_with_vars(
+            [
+                (constants::SPIN_SCRATCH_DIR_ENV, Some(&scratch_dir)),
+                (constants::SPIN_LAZY_DATA_LAYERS_ENV, None),
+            ],
+            source.to_locked_app(&cache),
+        )
+        .await
+        .expect("to_locked_app failed");
+
+        assert!(lazy_files.is_some());
+        let mount_dir = url::Url::from_directory_path(scratch_dir.join("assets").join("assets"))
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            locked_app.components[0].files[0].content.source.as_deref(),
+            Some(mount_dir.as_str())
         );
     }
 
@@ -424,7 +883,7 @@ mod tests {
         assert!(result.is_ok(), "to_locked_app failed: {:?}", result.err());
         // Assert that the LockedApp contains one component whose source URI
         // points at the wasm file (spin_loader encodes it as a file:// URI).
-        let locked_app = result.unwrap();
+        let (locked_app, ..) = result.unwrap();
         assert_eq!(locked_app.components.len(), 1);
         let component = &locked_app.components[0];
         let expected_uri = format!("file://{}", wasm_path.to_str().unwrap());
@@ -1,12 +1,15 @@
-use std::{collections::HashSet, path::Path};
+use std::{path::Path, sync::Arc};
 
 use anyhow::Result;
 use futures::{future::BoxFuture, FutureExt};
 use log::{debug, info};
 use spin_app::{locked::LockedApp, App};
+use spin_factor_wasi::{spin::SpinFilesMounter, WasiFactor};
+use spin_factors::RuntimeFactors;
+use spin_factors_executor::{FactorsExecutor, InstanceState};
 use spin_runtime_factors::{FactorsBuilder, TriggerAppArgs, TriggerFactors};
 use spin_trigger::{
-    cli::{FactorsConfig, TriggerAppBuilder, UserProvidedPath},
+    cli::{FactorsConfig, RuntimeFactorsBuilder, TriggerAppBuilder, UserProvidedPath},
     loader::ComponentLoader,
     Trigger,
 };
@@ -16,7 +19,13 @@ use trigger_command::CommandTrigger;
 use trigger_mqtt::MqttTrigger;
 use trigger_sqs::SqsTrigger;
 
-use crate::constants::{RUNTIME_CONFIG_PATH, SPIN_TRIGGER_WORKING_DIR};
+use crate::{
+    constants::{RUNTIME_CONFIG_PATH, SPIN_TRIGGER_WORKING_DIR},
+    lazy_files::{LazyFiles, LazyFilesMounter},
+    node_config,
+    source::files_read_only,
+    utils::scratch_dir,
+};
 
 pub(crate) const HTTP_TRIGGER_TYPE: &str = <HttpTrigger as Trigger<TriggerFactors>>::TYPE;
 pub(crate) const REDIS_TRIGGER_TYPE: &str = <RedisTrigger as Trigger<TriggerFactors>>::TYPE;
@@ -24,19 +33,18 @@ pub(crate) const SQS_TRIGGER_TYPE: &str = <SqsTrigger as Trigger<TriggerFactors>
 pub(crate) const MQTT_TRIGGER_TYPE: &str = <MqttTrigger as Trigger<TriggerFactors>>::TYPE;
 pub(crate) const COMMAND_TRIGGER_TYPE: &str = <CommandTrigger as Trigger<TriggerFactors>>::TYPE;
 
-/// Run the trigger with the given CLI args, [`App`] and [`ComponentLoader`].
+/// Run the trigger with the given CLI args, [`App`] and [`ComponentLoader`], mounting the
+/// lazily written files of the app, if any.
 pub(crate) async fn run<T>(
     cli_args: T::CliArgs,
     app: App,
     loader: &ComponentLoader,
+    lazy_files: Option<Arc<LazyFiles>>,
 ) -> Result<BoxFuture<'static, Result<()>>>
 where
     T: Trigger<TriggerFactors> + 'static,
 {
-    info!(" >>> running {} trigger", T::TYPE);
-    let trigger = T::new(cli_args, &app)?;
-    let builder: TriggerAppBuilder<_, FactorsBuilder> = TriggerAppBuilder::new(trigger);
-    let builder_args = match std::env::var("SPIN_MAX_INSTANCE_MEMORY") {
+    let trigger_app_args = match std::env::var("SPIN_MAX_INSTANCE_MEMORY") {
         Ok(limit) => {
             debug!("Setting instance max memory to {limit} bytes");
             let mut args = TriggerAppArgs::default();
@@ -45,12 +53,94 @@ where
         }
         Err(_) => Default::default(),
     };
+    let builder_args = ShimFactorsArgs {
+        trigger_app_args,
+        lazy_files,
+    };
+    run_with_args::<T>(cli_args, app, loader, builder_args).await
+}
+
+// Runs the trigger like `run`, with the given factors builder args
+async fn run_with_args<T>(
+    cli_args: T::CliArgs,
+    app: App,
+    loader: &ComponentLoader,
+    builder_args: ShimFactorsArgs,
+) -> Result<BoxFuture<'static, Result<()>>>
+where
+    T: Trigger<TriggerFactors> + 'static,
+{
+    info!(" >>> running {} trigger", T::TYPE);
+    let trigger = T::new(cli_args, &app)?;
+    let mut builder: TriggerAppBuilder<_, ShimFactorsBuilder> = TriggerAppBuilder::new(trigger);
+    // Execute components with the same engine options they were precompiled with
+    node_config::get()?.engine.apply(builder.engine_config());
     let future = builder
         .run(app, factors_config(), builder_args, loader)
         .await?;
     Ok(future.boxed())
 }
 
+/// Instance state of the components run by the triggers.
+pub(crate) type HostInstanceState =
+    InstanceState<<TriggerFactors as RuntimeFactors>::InstanceState, ()>;
+
+/// Returns an engine whose linker is set up by the same runtime factors as the triggers, and so
+/// provides components the same host interfaces.
+pub(crate) fn host_engine() -> Result<spin_core::Engine<HostInstanceState>> {
+    let config = node_config::get()?.engine.spin_config();
+    let (mut factors, _) = FactorsBuilder::build(&factors_config(), &TriggerAppArgs::default())?;
+    let mut builder = spin_core::Engine::builder(&config)?;
+    factors.init(builder.linker())?;
+    Ok(builder.build())
+}
+
+/// Builds the factors like Spin does, mounting the files of components with the
+/// [`LazyFilesMounter`] when their data layers are written on first mount, and read-only in the
+/// read-only files mode.
+struct ShimFactorsBuilder;
+
+/// Arguments of the [`ShimFactorsBuilder`]: those of Spin's builder, and the lazily written files
+/// of the app, which are not command line arguments.
+#[derive(clap::Args)]
+struct ShimFactorsArgs {
+    #[command(flatten)]
+    trigger_app_args: TriggerAppArgs,
+    #[arg(skip)]
+    lazy_files: Option<Arc<LazyFiles>>,
+}
+
+impl RuntimeFactorsBuilder for ShimFactorsBuilder {
+    type CliArgs = ShimFactorsArgs;
+    type Factors = <FactorsBuilder as RuntimeFactorsBuilder>::Factors;
+    type RuntimeConfig = <FactorsBuilder as RuntimeFactorsBuilder>::RuntimeConfig;
+
+    fn build(
+        config: &FactorsConfig,
+        args: &Self::CliArgs,
+    ) -> Result<(Self::Factors, Self::RuntimeConfig)> {
+        let (mut factors, runtime_config) = FactorsBuilder::build(config, &args.trigger_app_args)?;
+        // The read-only mode overrides the write access the trigger args grant to mounted files
+        let allow_transient_write =
+            args.trigger_app_args.allow_transient_write && !files_read_only();
+        let mounter = SpinFilesMounter::new(config.working_dir.clone(), allow_transient_write);
+        factors.wasi = match &args.lazy_files {
+            Some(lazy_files) => WasiFactor::new(LazyFilesMounter::new(lazy_files.clone(), mounter)),
+            None => WasiFactor::new(mounter),
+        };
+        Ok((factors, runtime_config))
+    }
+
+    fn configure_app<U: Send + 'static>(
+        executor: &mut FactorsExecutor<Self::Factors, U>,
+        runtime_config: &Self::RuntimeConfig,
+        config: &FactorsConfig,
+        args: &Self::CliArgs,
+    ) -> Result<()> {
+        FactorsBuilder::configure_app(executor, runtime_config, config, &args.trigger_app_args)
+    }
+}
+
 /// Configuration for the factors.
 fn factors_config() -> FactorsConfig {
     // Load in runtime config if one exists at expected location
@@ -63,8 +153,8 @@ fn factors_config() -> FactorsConfig {
         working_dir: SPIN_TRIGGER_WORKING_DIR.into(),
         runtime_config_file,
         // This is the default base for the state_dir (.spin) unless it is
-        // explicitly configured via the runtime config.
-        local_app_dir: Some(SPIN_TRIGGER_WORKING_DIR.to_string()),
+        // explicitly configured via the runtime config, so keep it writable.
+        local_app_dir: Some(scratch_dir().to_string_lossy().into_owned()),
         // Explicitly do not set log dir in order to force logs to be displayed to stdout.
         // Otherwise, would default to the state directory.
         log_dir: UserProvidedPath::Unset,
@@ -72,6 +162,18 @@ fn factors_config() -> FactorsConfig {
     }
 }
 
+/// Trigger types supported by the shim, in the order they are started.
+///
+/// The HTTP trigger is started last so that the backends of the other triggers
+/// (Redis, SQS, MQTT) are connected before the pod starts accepting requests.
+pub(crate) const SUPPORTED_TRIGGER_TYPES: [&str; 5] = [
+    REDIS_TRIGGER_TYPE,
+    SQS_TRIGGER_TYPE,
+    MQTT_TRIGGER_TYPE,
+    COMMAND_TRIGGER_TYPE,
+    HTTP_TRIGGER_TYPE,
+];
+
 /// get the supported trigger types from the `LockedApp`.
 ///
 /// this function filters the trigger types to only return the ones that are currently supported.
@@ -84,26 +186,159 @@ fn factors_config() -> FactorsConfig {
 /// - mqtt
 /// - command
 ///
-/// Note: this function returns a `HashSet` of supported trigger types. Duplicates are removed.
-pub(crate) fn get_supported_triggers(locked_app: &LockedApp) -> anyhow::Result<HashSet<String>> {
-    let supported_triggers: HashSet<&str> = HashSet::from([
-        HTTP_TRIGGER_TYPE,
-        REDIS_TRIGGER_TYPE,
-        SQS_TRIGGER_TYPE,
-        COMMAND_TRIGGER_TYPE,
-        MQTT_TRIGGER_TYPE,
-    ]);
-
-    locked_app.triggers.iter()
-        .map(|trigger| {
-            let trigger_type = &trigger.trigger_type;
-            if !supported_triggers.contains(trigger_type.as_str()) {
-                Err(anyhow::anyhow!(
-                    "Only Http, Redis, MQTT, SQS, and Command triggers are currently supported. Found unsupported trigger: {trigger_type:?}"
-                ))
-            } else {
-                Ok(trigger_type.clone())
-            }
+/// Note: duplicates are removed and the returned trigger types are ordered
+/// according to [`SUPPORTED_TRIGGER_TYPES`], so the start order is the same on every run.
+pub(crate) fn get_supported_triggers(locked_app: &LockedApp) -> anyhow::Result<Vec<String>> {
+    if let Some(trigger) = locked_app
+        .triggers
+        .iter()
+        .find(|trigger| !SUPPORTED_TRIGGER_TYPES.contains(&trigger.trigger_type.as_str()))
+    {
+        anyhow::bail!(
+            "Only Http, Redis, MQTT, SQS, and Command triggers are currently supported. Found unsupported trigger: {:?}",
+            trigger.trigger_type
+        );
+    }
+
+    Ok(SUPPORTED_TRIGGER_TYPES
+        .iter()
+        .filter(|supported| {
+            locked_app
+                .triggers
+                .iter()
+                .any(|trigger| trigger.trigger_type == **supported)
         })
-        .collect::<anyhow::Result<HashSet<_>>>()
+        .map(|supported| supported.to_string())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locked_app_with_triggers(trigger_types: &[&str]) -> LockedApp {
+        let triggers = trigger_types
+            .iter()
+            .enumerate()
+            .map(|(i, trigger_type)| {
+                format!(
+                    r#"{{ "id": "trigger-{i}", "trigger_type": "{trigger_type}", "trigger_config": {{}} }}"#
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let app_json = format!(
+            r#"{{
+                "spin_lock_version": 1,
+                "components": [],
+                "triggers": [{triggers}]
+            }}"#
+        );
+        LockedApp::from_json(app_json.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn supported_triggers_are_ordered_with_http_last() {
+        let locked_app = locked_app_with_triggers(&[
+            HTTP_TRIGGER_TYPE,
+            MQTT_TRIGGER_TYPE,
+            HTTP_TRIGGER_TYPE,
+            REDIS_TRIGGER_TYPE,
+        ]);
+
+        let triggers = get_supported_triggers(&locked_app).unwrap();
+
+        assert_eq!(
+            triggers,
+            vec![REDIS_TRIGGER_TYPE, MQTT_TRIGGER_TYPE, HTTP_TRIGGER_TYPE]
+        );
+    }
+
+    #[test]
+    fn unsupported_trigger_returns_error() {
+        let locked_app = locked_app_with_triggers(&[HTTP_TRIGGER_TYPE, "timer"]);
+
+        let err = get_supported_triggers(&locked_app).unwrap_err().to_string();
+
+        assert!(err.contains("\"timer\""), "unexpected error message: {err}");
+    }
+
+    // Runs a command component creating `written.txt` in the directory mounted at its root, with
+    // transient writes allowed by the trigger args
+    async fn run_file_writer(files_dir: &Path, read_only: bool) -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let module = wat::parse_str(
+            r#"(module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 16) "written.txt")
+                ;; Opens written.txt with O_CREAT and FD_WRITE in the first preopened directory
+                (func (export "_start")
+                    (drop (call $path_open (i32.const 3) (i32.const 0) (i32.const 16) (i32.const 11)
+                        (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 0))))
+            )"#,
+        )?;
+        let wasm_path = dir.path().join("writer.wasm");
+        std::fs::write(
+            &wasm_path,
+            spin_componentize::componentize_if_necessary(&module)?,
+        )?;
+        let source = url::Url::from_file_path(&wasm_path).unwrap();
+        let files = url::Url::from_directory_path(files_dir).unwrap();
+        let app_json = format!(
+            r#"{{
+                "spin_lock_version": 1,
+                "components": [{{
+                    "id": "writer",
+                    "source": {{ "content_type": "application/wasm", "content": {{ "source": "{source}" }} }},
+                    "files": [{{ "content": {{ "source": "{files}" }}, "path": "/" }}]
+                }}],
+                "triggers": [{{ "id": "trigger", "trigger_type": "{COMMAND_TRIGGER_TYPE}", "trigger_config": {{ "component": "writer" }} }}]
+            }}"#
+        );
+        let app = App::new(
+            Arc::<str>::from("writer"),
+            LockedApp::from_json(app_json.as_bytes())?,
+        );
+        let mut trigger_app_args = TriggerAppArgs::default();
+        trigger_app_args.allow_transient_write = true;
+        let builder_args = ShimFactorsArgs {
+            trigger_app_args,
+            lazy_files: None,
+        };
+        let cli_args = trigger_command::CliArgs { guest_args: vec![] };
+        let read_only = read_only.to_string();
+        temp_env::async_with_vars(
+            [
+                (
+                    crate::constants::SPIN_FILES_READ_ONLY_ENV,
+                    Some(read_only.as_str()),
+                ),
+                (crate::constants::SPIN_SCRATCH_DIR_ENV, dir.path().to_str()),
+            ],
+            async {
+                let loader = ComponentLoader::default();
+                run_with_args::<CommandTrigger>(cli_args, app, &loader, builder_args)
+                    .await?
+                    .await
+            },
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn read_only_files_cannot_be_written() {
+        let files_dir = tempfile::tempdir().unwrap();
+        run_file_writer(files_dir.path(), true)
+            .await
+            .expect("running the component failed");
+        assert!(!files_dir.path().join("written.txt").exists());
+
+        // The same component writes the file when files are not read-only
+        run_file_writer(files_dir.path(), false)
+            .await
+            .expect("running the component failed");
+        assert!(files_dir.path().join("written.txt").exists());
+    }
 }
@@ -1,23 +1,56 @@
 use std::{
     env,
+    hash::{Hash, Hasher},
+    io,
     net::{SocketAddr, ToSocketAddrs},
-    path::PathBuf,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    thread,
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use containerd_shim_wasm::sandbox::context::WasmLayer;
-use oci_spec::image::MediaType;
+use oci_spec::image::{Descriptor, DigestAlgorithm, MediaType};
+use sha2::{Digest as _, Sha256, Sha384, Sha512};
 use spin_app::locked::LockedApp;
 use spin_loader::cache::Cache;
 
-use crate::constants;
+use crate::{constants, node_config, precompiled};
 
-// create a cache directory at /.cache
+// Returns the writable directory the shim keeps its own state in, the container root by default
+pub(crate) fn scratch_dir() -> PathBuf {
+    env::var_os(constants::SPIN_SCRATCH_DIR_ENV)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(constants::SPIN_SCRATCH_DIR_DEFAULT))
+}
+
+// Returns the id of the running application.
+// The `HOSTNAME` environment variable should contain the fully unique container name
+pub(crate) fn app_id() -> String {
+    env::var("HOSTNAME").unwrap_or_else(|_| "unknown".into())
+}
+
+// Checks that the shim can write to its scratch directory, so that a read-only root filesystem
+// fails the container with a clear error rather than on the first write
+pub(crate) fn check_scratch_dir_writable(dir: &Path) -> Result<()> {
+    tempfile::tempfile_in(dir).map(drop).map_err(|err| {
+        anyhow!(
+            "scratch directory {dir:?} is not writable: {err}. With a read-only root filesystem, mount a writable volume, such as an emptyDir, and set {} to its path",
+            constants::SPIN_SCRATCH_DIR_ENV
+        )
+    })
+}
+
+// create a cache directory at .cache in the scratch directory, or use the
+// shared cache directory of the node if one is given
 // this is needed for the spin LocalLoader to work
 // TODO: spin should provide a more flexible `loader::from_file` that
 // does not assume the existence of a cache directory
-pub(crate) async fn initialize_cache() -> Result<Cache, anyhow::Error> {
-    let cache_dir = PathBuf::from("/.cache");
+pub(crate) async fn initialize_cache(shared_dir: Option<&Path>) -> Result<Cache, anyhow::Error> {
+    let cache_dir = shared_dir
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| scratch_dir().join(".cache"));
     let cache = Cache::new(Some(cache_dir.clone()))
         .await
         .context("failed to create cache")?;
@@ -25,22 +58,180 @@ pub(crate) async fn initialize_cache() -> Result<Cache, anyhow::Error> {
     Ok(cache)
 }
 
-pub(crate) async fn handle_archive_layer(
-    cache: &Cache,
-    bytes: impl AsRef<[u8]>,
-    digest: impl AsRef<str>,
-) -> Result<()> {
-    // spin_oci::client::unpack_archive_layer attempts to create a tempdir via tempfile::tempdir()
-    // which will fall back to /tmp if TMPDIR is not set. /tmp is either not found or not accessible
-    // in the shim environment, hence setting to current working directory.
-    if env::var("TMPDIR").is_err() {
-        log::debug!(
-            "<<< TMPDIR is not set; setting to current working directory for unpacking archive layer"
+// Checks that the content of a layer can be trusted, so that corrupted or tampered content is
+// never written to the cache or executed.
+//
+// Runwasi substitutes the content of wasm layers with their precompiled artifact while keeping
+// the original descriptor, so precompiled content must have been recorded by the shim as
+// precompiled from the layer. Any other content must match the size and digest of its descriptor,
+// including precompiled layers shipped by images, which are only trusted if the node config says
+// so.
+pub(crate) fn verify_layer(layer: &WasmLayer) -> Result<()> {
+    let descriptor = &layer.config;
+    if wasmtime::Engine::detect_precompiled(&layer.layer).is_some() {
+        let digest = descriptor.digest().to_string();
+        if precompiled::is_recorded(&layer.layer, &digest) {
+            return Ok(());
+        }
+        if !precompiled::trust_image_precompiled() {
+            bail!("precompiled content of layer {digest} was not precompiled by the shim");
+        }
+    }
+    verify_descriptor(layer)
+}
+
+// Checks that the content of a layer matches the size and digest of its descriptor
+pub(crate) fn verify_descriptor(layer: &WasmLayer) -> Result<()> {
+    let descriptor = &layer.config;
+    let size = layer.layer.len() as u64;
+    if size != descriptor.size() {
+        bail!(
+            "layer {} of media type {} has size {size}, expected {}",
+            descriptor.digest(),
+            descriptor.media_type(),
+            descriptor.size()
+        );
+    }
+    let digest = match descriptor.digest().algorithm() {
+        DigestAlgorithm::Sha256 => format!("{:x}", Sha256::digest(&layer.layer)),
+        DigestAlgorithm::Sha384 => format!("{:x}", Sha384::digest(&layer.layer)),
+        DigestAlgorithm::Sha512 => format!("{:x}", Sha512::digest(&layer.layer)),
+        algorithm => bail!(
+            "layer {} of media type {} uses unsupported digest algorithm {algorithm}",
+            descriptor.digest(),
+            descriptor.media_type()
+        ),
+    };
+    if digest != descriptor.digest().digest() {
+        bail!(
+            "layer {} of media type {} does not match its digest: content hashes to {}:{digest}",
+            descriptor.digest(),
+            descriptor.media_type(),
+            descriptor.digest().algorithm()
         );
-        env::set_var("TMPDIR", env::current_dir().unwrap_or(".".into()));
+    }
+    Ok(())
+}
+
+// Returns the decompressed layer if the layer is a gzip or zstd compressed wasm layer, otherwise
+// None. The layer keeps the descriptor of the compressed content, with the media type of the
+// decompressed content. Data layers are streamed with `write_data_content` instead.
+pub(crate) fn decompress_wasm_layer(layer: &WasmLayer) -> Result<Option<WasmLayer>> {
+    let Some((config, gzip)) = decompressed_descriptor(layer) else {
+        return Ok(None);
+    };
+    if config.media_type() != &MediaType::Other(constants::OCI_LAYER_MEDIA_TYPE_WASM.to_string()) {
+        return Ok(None);
+    }
+    // Runwasi substitutes the content of compiled layers with their precompiled artifact
+    if wasmtime::Engine::detect_precompiled(&layer.layer).is_some() {
+        return Ok(Some(WasmLayer {
+            layer: layer.layer.clone(),
+            config,
+        }));
+    }
+    let mut content = Vec::new();
+    decompress_into(layer, gzip, max_decompressed_layer_size()?, &mut content)?;
+    Ok(Some(WasmLayer {
+        layer: content,
+        config,
+    }))
+}
+
+// Returns the media type of the content of a layer: the media type of the decompressed content
+// of a gzip or zstd compressed layer, otherwise the media type of the layer
+pub(crate) fn content_media_type(layer: &WasmLayer) -> MediaType {
+    match decompressed_descriptor(layer) {
+        Some((config, _)) => config.media_type().clone(),
+        None => layer.config.media_type().clone(),
+    }
+}
+
+// Writes the content of a data layer to `writer`, streaming the decompressed content of a gzip
+// or zstd compressed layer rather than decompressing it in memory
+pub(crate) fn write_data_content(layer: &WasmLayer, writer: &mut impl io::Write) -> Result<()> {
+    match decompressed_descriptor(layer) {
+        Some((_, gzip)) => decompress_into(layer, gzip, max_decompressed_layer_size()?, writer),
+        None => writer
+            .write_all(&layer.layer)
+            .with_context(|| format!("failed to write layer {}", layer.config.digest())),
+    }
+}
+
+// Returns the maximum size of the decompressed content of a layer set by the node config
+fn max_decompressed_layer_size() -> Result<u64> {
+    Ok(node_config::get()?
+        .max_decompressed_layer_size
+        .unwrap_or(constants::MAX_DECOMPRESSED_LAYER_SIZE_DEFAULT))
+}
+
+// Returns the descriptor of the decompressed content of a gzip or zstd compressed wasm or data
+// layer, and whether it is gzip compressed
+fn decompressed_descriptor(layer: &WasmLayer) -> Option<(Descriptor, bool)> {
+    let MediaType::Other(name) = layer.config.media_type() else {
+        return None;
+    };
+    let (media_type, gzip) = match name.as_str() {
+        constants::OCI_LAYER_MEDIA_TYPE_WASM_GZIP => (constants::OCI_LAYER_MEDIA_TYPE_WASM, true),
+        constants::OCI_LAYER_MEDIA_TYPE_WASM_ZSTD => (constants::OCI_LAYER_MEDIA_TYPE_WASM, false),
+        constants::OCI_LAYER_MEDIA_TYPE_DATA_GZIP => (spin_oci::client::DATA_MEDIATYPE, true),
+        constants::OCI_LAYER_MEDIA_TYPE_DATA_ZSTD => (spin_oci::client::DATA_MEDIATYPE, false),
+        _ => return None,
+    };
+    let mut config = layer.config.clone();
+    config.set_media_type(MediaType::Other(media_type.to_string()));
+    Some((config, gzip))
+}
+
+// Streams the decompressed content of a layer into `writer` with a streaming decoder, failing
+// once it exceeds `max_size` bytes, so that a small compressed layer cannot exhaust the memory or
+// disk of the node
+fn decompress_into(
+    layer: &WasmLayer,
+    gzip: bool,
+    max_size: u64,
+    writer: &mut impl io::Write,
+) -> Result<()> {
+    let context = || {
+        format!(
+            "failed to decompress layer {} of media type {}",
+            layer.config.digest(),
+            layer.config.media_type()
+        )
+    };
+    let decoder: Box<dyn io::Read> = if gzip {
+        Box::new(flate2::read::GzDecoder::new(layer.layer.as_slice()))
+    } else {
+        Box::new(zstd::stream::read::Decoder::new(layer.layer.as_slice()).with_context(context)?)
+    };
+    // Read one byte past the limit to tell content of exactly `max_size` bytes from larger content
+    let size =
+        io::copy(&mut io::Read::take(decoder, max_size + 1), writer).with_context(context)?;
+    if size > max_size {
+        return Err(anyhow!("decompressed content exceeds {max_size} bytes")).with_context(context);
+    }
+    Ok(())
+}
+
+// Returns the hex encoded SHA-256 hash of `value`, for keys that must be stable across processes
+// and shim versions, which `DefaultHasher` does not guarantee
+pub(crate) fn sha256_hash(value: &impl Hash) -> String {
+    struct Sha256Hasher(Sha256);
+
+    impl Hasher for Sha256Hasher {
+        fn write(&mut self, bytes: &[u8]) {
+            self.0.update(bytes);
+        }
+
+        fn finish(&self) -> u64 {
+            let digest = self.0.clone().finalize();
+            u64::from_le_bytes(digest[..8].try_into().unwrap())
+        }
     }
 
-    spin_oci::client::unpack_archive_layer(cache, bytes, digest).await
+    let mut hasher = Sha256Hasher(Sha256::new());
+    value.hash(&mut hasher);
+    format!("{:x}", hasher.0.finalize())
 }
 
 // Returns Some(WasmLayer) if the layer contains wasm, otherwise None
@@ -55,6 +246,42 @@ pub(crate) fn is_wasm_content(layer: &WasmLayer) -> Option<WasmLayer> {
     None
 }
 
+// Returns the directory in which components of file-based applications and composed components
+// are precompiled: the node directory, unless it cannot be created
+pub(crate) fn precompile_cache_dir() -> PathBuf {
+    // Tests compose and precompile layers too, and must not write to the node directory
+    if cfg!(test) {
+        return env::temp_dir().join("containerd-shim-spin-test-cache");
+    }
+    let node_dir = Path::new(constants::SPIN_PRECOMPILE_CACHE_DIR);
+    match std::fs::create_dir_all(node_dir) {
+        Ok(()) => node_dir.to_path_buf(),
+        Err(err) => {
+            log::warn!(
+                "failed to create precompile cache {node_dir:?}, precompiling into the scratch directory: {err}"
+            );
+            scratch_dir().join(constants::SPIN_PRECOMPILE_SCRATCH_CACHE_DIR)
+        }
+    }
+}
+
+// Returns the number of wasm layers that may be precompiled concurrently, as configured by
+// the precompile parallelism environment variable or the number of available CPUs
+pub(crate) fn precompile_parallelism() -> usize {
+    if let Ok(value) = env::var(constants::SPIN_PRECOMPILE_PARALLELISM_ENV) {
+        match value.parse::<NonZeroUsize>() {
+            Ok(parallelism) => return parallelism.get(),
+            Err(err) => log::warn!(
+                "ignoring invalid {} value {value:?}: {err}",
+                constants::SPIN_PRECOMPILE_PARALLELISM_ENV
+            ),
+        }
+    }
+    thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(1)
+}
+
 pub(crate) fn parse_addr(addr: &str) -> Result<SocketAddr> {
     let addrs: SocketAddr = addr
         .to_socket_addrs()?
@@ -137,6 +364,24 @@ mod tests {
         assert_eq!(parsed.ip().to_string(), "0.0.0.0");
     }
 
+    #[test]
+    fn precompile_parallelism_from_env() {
+        temp_env::with_var(
+            constants::SPIN_PRECOMPILE_PARALLELISM_ENV,
+            Some("3"),
+            || {
+                assert_eq!(precompile_parallelism(), 3);
+            },
+        );
+        temp_env::with_var(
+            constants::SPIN_PRECOMPILE_PARALLELISM_ENV,
+            Some("0"),
+            || {
+                assert!(precompile_parallelism() >= 1);
+            },
+        );
+    }
+
     #[test]
     fn is_wasm_content_test() {
         let wasm_content = WasmLayer {
@@ -165,4 +410,120 @@ mod tests {
         assert!(is_wasm_content(&wasm_content).is_some());
         assert!(is_wasm_content(&data_content).is_none());
     }
+
+    #[test]
+    fn decompress_layer_test() {
+        let wasm = wat::parse_str("(module)").unwrap();
+        let make_layer = |media_type: &str, layer: Vec<u8>| WasmLayer {
+            layer,
+            config: oci_spec::image::Descriptor::new(
+                MediaType::Other(media_type.to_string()),
+                1024,
+                Digest::from_str(
+                    "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b",
+                )
+                .unwrap(),
+            ),
+        };
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        io::Write::write_all(&mut gzip, &wasm).unwrap();
+        let gzip_layer = make_layer(
+            constants::OCI_LAYER_MEDIA_TYPE_WASM_GZIP,
+            gzip.finish().unwrap(),
+        );
+        let decompressed = decompress_wasm_layer(&gzip_layer).unwrap().unwrap();
+        assert_eq!(decompressed.layer, wasm);
+        assert!(is_wasm_content(&decompressed).is_some());
+
+        // Data layers are not decompressed in memory, but streamed
+        let zstd_layer = make_layer(
+            constants::OCI_LAYER_MEDIA_TYPE_DATA_ZSTD,
+            zstd::encode_all(b"data".as_slice(), 0).unwrap(),
+        );
+        assert!(decompress_wasm_layer(&zstd_layer).unwrap().is_none());
+        assert_eq!(
+            content_media_type(&zstd_layer),
+            MediaType::Other(spin_oci::client::DATA_MEDIATYPE.to_string())
+        );
+        let mut content = Vec::new();
+        write_data_content(&zstd_layer, &mut content).unwrap();
+        assert_eq!(content, b"data");
+
+        let wasm_layer = make_layer(constants::OCI_LAYER_MEDIA_TYPE_WASM, wasm);
+        assert!(decompress_wasm_layer(&wasm_layer).unwrap().is_none());
+        let corrupted_layer =
+            make_layer(constants::OCI_LAYER_MEDIA_TYPE_WASM_GZIP, b"bad".to_vec());
+        assert!(decompress_wasm_layer(&corrupted_layer).is_err());
+    }
+
+    #[test]
+    fn decompressed_size_is_capped() {
+        let zeros = vec![0; 64 * 1024];
+        let layer = WasmLayer {
+            layer: zstd::encode_all(zeros.as_slice(), 0).unwrap(),
+            config: oci_spec::image::Descriptor::new(
+                MediaType::Other(constants::OCI_LAYER_MEDIA_TYPE_DATA_ZSTD.to_string()),
+                1024,
+                Digest::from_str(
+                    "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b",
+                )
+                .unwrap(),
+            ),
+        };
+
+        let mut content = Vec::new();
+        decompress_into(&layer, false, zeros.len() as u64, &mut content).unwrap();
+        assert_eq!(content, zeros);
+
+        let mut content = Vec::new();
+        let err = decompress_into(&layer, false, zeros.len() as u64 - 1, &mut content).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("exceeds"),
+            "unexpected error message: {err:#}"
+        );
+        assert_eq!(content.len(), zeros.len());
+    }
+
+    #[test]
+    fn read_only_scratch_dir_is_detected() {
+        let root = tempfile::tempdir().unwrap();
+        check_scratch_dir_writable(root.path()).unwrap();
+
+        // Nothing can be created under a regular file, whatever the privileges of the test, like
+        // under a read-only mount
+        let file = root.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let err = check_scratch_dir_writable(&file).unwrap_err().to_string();
+        assert!(
+            err.contains(constants::SPIN_SCRATCH_DIR_ENV),
+            "unexpected error message: {err}"
+        );
+
+        temp_env::with_var(
+            constants::SPIN_SCRATCH_DIR_ENV,
+            Some(root.path().to_str().unwrap()),
+            || assert_eq!(scratch_dir(), root.path()),
+        );
+    }
+
+    #[test]
+    fn read_only_scratch_dir_root_is_detected() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        // Root bypasses the permissions of the directory
+        // SAFETY: geteuid has no preconditions and cannot fail
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        let root = tempfile::tempdir().unwrap();
+        std::fs::set_permissions(root.path(), std::fs::Permissions::from_mode(0o555)).unwrap();
+        let result = check_scratch_dir_writable(root.path());
+        std::fs::set_permissions(root.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+        let err = result.unwrap_err().to_string();
+        assert!(
+            err.contains("is not writable") && err.contains(constants::SPIN_SCRATCH_DIR_ENV),
+            "unexpected error message: {err}"
+        );
+    }
 }
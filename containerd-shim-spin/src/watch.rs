@@ -0,0 +1,223 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use spin_app::locked::LockedApp;
+use spin_manifest::schema::v2::WasiFilesMount;
+
+use crate::constants;
+
+/// Interval at which watched files are polled for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Modification time and size of each watched file
+type Fingerprint = BTreeMap<PathBuf, Option<(Option<SystemTime>, u64)>>;
+
+/// Returns whether file-based applications are reloaded when their files change, see
+/// [`constants::SPIN_DEV_WATCH_ENV`].
+pub(crate) fn watch_enabled() -> bool {
+    env::var(constants::SPIN_DEV_WATCH_ENV).is_ok_and(|watch| watch == "true")
+}
+
+/// Source of the changes that reload a running application.
+pub(crate) trait Watch {
+    /// Waits until the application changes.
+    async fn changed(&mut self);
+}
+
+/// Watches the manifest of a file-based application and the sources and files of its components
+/// for changes, by polling their modification times and sizes.
+pub(crate) struct AppWatcher {
+    paths: Vec<PathBuf>,
+    fingerprint: Fingerprint,
+}
+
+impl AppWatcher {
+    /// Watches `manifest`, the files it mounts into components and the local sources of the
+    /// components of `locked_app`, which must not yet point at precompiled components. Files are
+    /// taken from the manifest, as those of `locked_app` are copies with the `copy` files mount
+    /// strategy.
+    pub(crate) fn new(manifest: &Path, locked_app: &LockedApp) -> Result<Self> {
+        let mut paths = vec![manifest.to_path_buf()];
+        paths.extend(source_paths(locked_app));
+        paths.extend(file_mount_paths(manifest)?);
+        paths.sort();
+        paths.dedup();
+        let fingerprint = fingerprint(&paths);
+        Ok(Self { paths, fingerprint })
+    }
+}
+
+impl Watch for AppWatcher {
+    /// Waits until the watched files change, then until they stop changing, so that the
+    /// application is not reloaded in the middle of a build.
+    async fn changed(&mut self) {
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let mut current = fingerprint(&self.paths);
+            if current == self.fingerprint {
+                continue;
+            }
+            loop {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                let next = fingerprint(&self.paths);
+                if next == current {
+                    break;
+                }
+                current = next;
+            }
+            self.fingerprint = current;
+            return;
+        }
+    }
+}
+
+// Returns the paths of the local sources of the components of `locked_app` and of their
+// dependencies
+fn source_paths(locked_app: &LockedApp) -> impl Iterator<Item = PathBuf> + '_ {
+    locked_app
+        .components
+        .iter()
+        .flat_map(|component| {
+            std::iter::once(&component.source).chain(
+                component
+                    .dependencies
+                    .values()
+                    .map(|dependency| &dependency.source),
+            )
+        })
+        .filter_map(|source| source.content.source.as_deref())
+        .filter_map(|source| url::Url::parse(source).ok()?.to_file_path().ok())
+}
+
+// Returns the paths of the files `manifest` mounts into components. Glob patterns are watched from
+// the directory of their first wildcard, so that files matching them once created are watched too.
+fn file_mount_paths(manifest: &Path) -> Result<Vec<PathBuf>> {
+    let app_manifest = spin_manifest::manifest_from_file(manifest)
+        .with_context(|| format!("failed to read manifest {manifest:?}"))?;
+    let manifest_dir = manifest.parent().unwrap_or(Path::new("."));
+    Ok(app_manifest
+        .components
+        .values()
+        .flat_map(|component| &component.files)
+        .map(|mount| match mount {
+            WasiFilesMount::Pattern(pattern) => manifest_dir.join(glob_base(pattern)),
+            WasiFilesMount::Placement { source, .. } => manifest_dir.join(source),
+        })
+        .collect())
+}
+
+// Returns the leading components of a glob pattern without wildcards
+fn glob_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| {
+            !component
+                .as_os_str()
+                .to_string_lossy()
+                .contains(['*', '?', '[', '{'])
+        })
+        .collect()
+}
+
+// Fingerprints the files at `paths`, and the files of the directories among them
+fn fingerprint(paths: &[PathBuf]) -> Fingerprint {
+    let mut fingerprint = Fingerprint::new();
+    for path in paths {
+        let Ok(metadata) = fs::metadata(path) else {
+            fingerprint.insert(path.clone(), None);
+            continue;
+        };
+        if !metadata.is_dir() {
+            fingerprint.insert(
+                path.clone(),
+                Some((metadata.modified().ok(), metadata.len())),
+            );
+            continue;
+        }
+        // Symbolic links within directories are not followed, so that link cycles terminate
+        let mut dirs = vec![path.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                } else {
+                    fingerprint.insert(
+                        entry.path(),
+                        Some((metadata.modified().ok(), metadata.len())),
+                    );
+                }
+            }
+        }
+    }
+    fingerprint
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_detects_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("spin.toml");
+        let assets = dir.path().join("assets");
+        fs::create_dir_all(assets.join("css")).unwrap();
+        fs::write(&manifest, "spin_manifest_version = 2").unwrap();
+        fs::write(assets.join("css/style.css"), "body {}").unwrap();
+        let paths = vec![manifest.clone(), assets.clone()];
+
+        let before = fingerprint(&paths);
+        assert_eq!(before, fingerprint(&paths));
+
+        fs::write(assets.join("css/style.css"), "body { margin: 0 }").unwrap();
+        let after = fingerprint(&paths);
+        assert_ne!(before, after);
+
+        fs::remove_file(&manifest).unwrap();
+        assert_ne!(after, fingerprint(&paths));
+    }
+
+    #[test]
+    fn file_mounts_are_watched_from_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("spin.toml");
+        fs::write(
+            &manifest,
+            r#"
+spin_manifest_version = 2
+
+[application]
+name = "app"
+
+[[trigger.http]]
+route = "/..."
+component = "web"
+
+[component.web]
+source = "web.wasm"
+files = ["static/**/*", "index.html", { source = "data", destination = "/data" }]
+"#,
+        )
+        .unwrap();
+
+        let mut paths = file_mount_paths(&manifest).unwrap();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                dir.path().join("data"),
+                dir.path().join("index.html"),
+                dir.path().join("static"),
+            ]
+        );
+        assert_eq!(glob_base("*.html"), PathBuf::new());
+    }
+}
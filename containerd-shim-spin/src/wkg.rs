@@ -0,0 +1,317 @@
+use std::{collections::HashSet, env};
+
+use anyhow::{bail, Context, Result};
+use spin_app::locked::{LockedApp, LockedTrigger};
+use wasmparser::{ComponentExternalKind, Encoding, Parser, Payload};
+
+use crate::{
+    capabilities::{component_interfaces, split_interface},
+    constants,
+    trigger::{COMMAND_TRIGGER_TYPE, HTTP_TRIGGER_TYPE},
+};
+
+/// A `application/wasm` layer of a Wasm OCI artifact.
+#[derive(Clone, Debug)]
+pub(crate) struct WkgLayer {
+    pub(crate) digest: String,
+    /// Whether the layer holds a WIT package rather than a runnable component
+    pub(crate) wit_package: bool,
+}
+
+/// Selects the layer holding the component to run among the `application/wasm` layers of an
+/// artifact.
+///
+/// The runtime does not hand the artifact config to the shim with the layers, so layers are
+/// selected by their content: WIT packages are skipped, as are duplicate layers, and exactly one
+/// runnable component must remain.
+pub(crate) fn select_component_layer(layers: &[WkgLayer]) -> Result<&WkgLayer> {
+    let mut digests = HashSet::new();
+    let candidates = layers
+        .iter()
+        .filter(|layer| !layer.wit_package)
+        .filter(|layer| digests.insert(&layer.digest))
+        .collect::<Vec<_>>();
+    match candidates[..] {
+        [layer] => Ok(layer),
+        [] => bail!(
+            "wasm package has no runnable component layer among {:?}",
+            layers.iter().map(|layer| &layer.digest).collect::<Vec<_>>()
+        ),
+        _ => bail!(
+            "expected a single runnable component layer in wasm package, found {:?}",
+            candidates
+                .iter()
+                .map(|layer| &layer.digest)
+                .collect::<Vec<_>>()
+        ),
+    }
+}
+
+/// Sets the trigger of the app synthesized for the component of a wasm package from the world
+/// the component targets, as Spin gives every bare component an HTTP trigger.
+///
+/// Components exporting `wasi:http/incoming-handler` (`wasi:http/proxy`) keep the HTTP trigger,
+/// while components exporting `wasi:cli/run` (`wasi:cli/command`) get the command trigger, which
+/// runs them with the container args. Core modules are left to Spin, which adapts them to HTTP.
+pub(crate) async fn configure_world_trigger(
+    locked_app: &mut LockedApp,
+    engine: &wasmtime::Engine,
+) -> Result<()> {
+    let [component] = &locked_app.components[..] else {
+        bail!(
+            "expected a single component in wasm package app, found {}",
+            locked_app.components.len()
+        );
+    };
+    // Precompiled sources of OCI applications are verified to be produced by the shim or trusted
+    // by the node config
+    let Some(interfaces) = component_interfaces(component, engine, true)
+        .await
+        .with_context(|| format!("failed to read exports of component {:?}", component.id))?
+    else {
+        return Ok(());
+    };
+    let exports = |interface: &str| {
+        interfaces
+            .exports
+            .iter()
+            .any(|export| split_interface(export) == split_interface(interface))
+    };
+    let trigger_type = if exports("wasi:http/incoming-handler") {
+        HTTP_TRIGGER_TYPE
+    } else if exports("wasi:cli/run") {
+        COMMAND_TRIGGER_TYPE
+    } else {
+        bail!(
+            "unsupported world: component {:?} exports {:?}, expected wasi:http/incoming-handler (wasi:http/proxy) or wasi:cli/run (wasi:cli/command)",
+            component.id,
+            interfaces.exports
+        );
+    };
+    log::info!(
+        "<<< running component {:?} with the {trigger_type} trigger",
+        component.id
+    );
+    if trigger_type != HTTP_TRIGGER_TYPE {
+        locked_app.triggers = vec![LockedTrigger {
+            id: format!("{}-{trigger_type}-trigger", component.id),
+            trigger_type: trigger_type.to_string(),
+            trigger_config: serde_json::json!({ "component": component.id }),
+        }];
+    }
+    Ok(())
+}
+
+/// Patches the app synthesized for the component of a wasm package with the settings a
+/// `spin.toml` would otherwise provide, as configured in the container environment by
+/// [`constants::SPIN_WKG_HTTP_ROUTE_ENV`], [`constants::SPIN_WKG_ALLOWED_OUTBOUND_HOSTS_ENV`],
+/// [`constants::SPIN_WKG_KEY_VALUE_STORES_ENV`] and [`constants::SPIN_WKG_VARIABLES_ENV`].
+pub(crate) fn configure_from_env(locked_app: &mut LockedApp) -> Result<()> {
+    if let Some(route) = env_var(constants::SPIN_WKG_HTTP_ROUTE_ENV) {
+        let http_triggers = locked_app
+            .triggers
+            .iter_mut()
+            .filter(|trigger| trigger.trigger_type == HTTP_TRIGGER_TYPE)
+            .collect::<Vec<_>>();
+        if http_triggers.is_empty() {
+            bail!(
+                "{} is set but the wasm package component does not run with the {HTTP_TRIGGER_TYPE} trigger",
+                constants::SPIN_WKG_HTTP_ROUTE_ENV
+            );
+        }
+        for trigger in http_triggers {
+            trigger.trigger_config["route"] = route.clone().into();
+        }
+    }
+
+    let [component] = &mut locked_app.components[..] else {
+        bail!(
+            "expected a single component in wasm package app, found {}",
+            locked_app.components.len()
+        );
+    };
+    for (name, key) in [
+        (
+            constants::SPIN_WKG_ALLOWED_OUTBOUND_HOSTS_ENV,
+            "allowed_outbound_hosts",
+        ),
+        (constants::SPIN_WKG_KEY_VALUE_STORES_ENV, "key_value_stores"),
+    ] {
+        if let Some(value) = env_var(name) {
+            component.metadata.insert(
+                key.to_string(),
+                split_list(&value).collect::<Vec<_>>().into(),
+            );
+        }
+    }
+
+    if let Some(variables) = env_var(constants::SPIN_WKG_VARIABLES_ENV) {
+        for variable in split_list(&variables) {
+            let (name, default) = match variable.split_once('=') {
+                Some((name, default)) => (name.trim(), Some(default.trim())),
+                None => (variable, None),
+            };
+            let declaration =
+                serde_json::from_value(serde_json::json!({ "default": default, "secret": false }))?;
+            locked_app.variables.insert(name.to_string(), declaration);
+            component
+                .config
+                .insert(name.to_string(), format!("{{{{ {name} }}}}"));
+        }
+    }
+    Ok(())
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// Returns whether `wasm` is an encoded WIT package, that is a component exporting only types,
+/// rather than a runnable component or core module.
+pub(crate) fn is_wit_package(wasm: &[u8]) -> Result<bool> {
+    if wasmtime::Engine::detect_precompiled(wasm).is_some() {
+        return Ok(false);
+    }
+    let mut depth = 0;
+    let mut exports = 0;
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload.context("failed to parse wasm")? {
+            Payload::Version { encoding, .. } => {
+                if depth == 0 && encoding == Encoding::Module {
+                    return Ok(false);
+                }
+                depth += 1;
+            }
+            Payload::End(_) => depth -= 1,
+            Payload::ComponentExportSection(reader) if depth == 1 => {
+                for export in reader {
+                    if export?.kind != ComponentExternalKind::Type {
+                        return Ok(false);
+                    }
+                    exports += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(exports > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(digest: &str, wit_package: bool) -> WkgLayer {
+        WkgLayer {
+            digest: digest.to_string(),
+            wit_package,
+        }
+    }
+
+    #[test]
+    fn wit_packages_are_detected() {
+        let package = wat::parse_str(
+            r#"(component (type $iface (instance)) (export "test:pkg/iface" (type $iface)))"#,
+        )
+        .unwrap();
+        assert!(is_wit_package(&package).unwrap());
+
+        let component = wat::parse_str(
+            r#"(component (import "wasi:cli/environment@0.2.0" (instance $env)) (export "wasi:cli/run@0.2.0" (instance $env)))"#,
+        )
+        .unwrap();
+        assert!(!is_wit_package(&component).unwrap());
+        assert!(!is_wit_package(&wat::parse_str("(module)").unwrap()).unwrap());
+    }
+
+    #[test]
+    fn component_layer_is_selected() {
+        let layers = [layer("sha256:a", true), layer("sha256:b", false)];
+        assert_eq!(select_component_layer(&layers).unwrap().digest, "sha256:b");
+
+        let layers = [layer("sha256:a", false), layer("sha256:b", false)];
+        assert!(select_component_layer(&layers).is_err());
+        let layers = [layer("sha256:a", false), layer("sha256:a", false)];
+        assert_eq!(select_component_layer(&layers).unwrap().digest, "sha256:a");
+
+        assert!(select_component_layer(&[layer("sha256:a", true)]).is_err());
+    }
+
+    async fn wasm_package_app(wat: &str) -> (LockedApp, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let wasm_path = dir.path().join("component.wasm");
+        std::fs::write(&wasm_path, wat::parse_str(wat).unwrap()).unwrap();
+        let locked_app = spin_loader::from_wasm_file(&wasm_path).await.unwrap();
+        (locked_app, dir)
+    }
+
+    #[tokio::test]
+    async fn command_component_gets_command_trigger() {
+        let (mut locked_app, _dir) = wasm_package_app(
+            r#"(component (import "wasi:cli/environment@0.2.0" (instance $env)) (export "wasi:cli/run@0.2.0" (instance $env)))"#,
+        )
+        .await;
+        configure_world_trigger(&mut locked_app, &wasmtime::Engine::default())
+            .await
+            .unwrap();
+        assert_eq!(locked_app.triggers.len(), 1);
+        assert_eq!(locked_app.triggers[0].trigger_type, COMMAND_TRIGGER_TYPE);
+        assert_eq!(
+            locked_app.triggers[0].trigger_config["component"],
+            locked_app.components[0].id.as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn component_of_unsupported_world_is_rejected() {
+        let (mut locked_app, _dir) = wasm_package_app(
+            r#"(component (import "test:pkg/iface" (instance $iface)) (export "test:pkg/other" (instance $iface)))"#,
+        )
+        .await;
+        let err = configure_world_trigger(&mut locked_app, &wasmtime::Engine::default())
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("unsupported world"),
+            "unexpected error message: {err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn wasm_package_app_is_configured_from_env() {
+        let (mut locked_app, _dir) = wasm_package_app("(component)").await;
+        temp_env::with_vars(
+            [
+                (constants::SPIN_WKG_HTTP_ROUTE_ENV, Some("/api/...")),
+                (
+                    constants::SPIN_WKG_ALLOWED_OUTBOUND_HOSTS_ENV,
+                    Some("https://example.com, redis://cache:6379"),
+                ),
+                (constants::SPIN_WKG_KEY_VALUE_STORES_ENV, Some("default")),
+                (constants::SPIN_WKG_VARIABLES_ENV, Some("api_key,region=eu")),
+            ],
+            || configure_from_env(&mut locked_app).unwrap(),
+        );
+
+        assert_eq!(locked_app.triggers[0].trigger_config["route"], "/api/...");
+        let component = &locked_app.components[0];
+        assert_eq!(
+            component.metadata["allowed_outbound_hosts"],
+            serde_json::json!(["https://example.com", "redis://cache:6379"])
+        );
+        assert_eq!(
+            component.metadata["key_value_stores"],
+            serde_json::json!(["default"])
+        );
+        assert_eq!(component.config["region"], "{{ region }}");
+        assert_eq!(locked_app.variables.len(), 2);
+    }
+}